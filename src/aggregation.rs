@@ -0,0 +1,114 @@
+use serde::{Deserialize, Serialize};
+
+/// A label of a metric, and whether any query uses it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelResult {
+    pub name: String,
+    pub values_count: usize,
+    pub used: bool,
+}
+
+/// A recommendation to aggregate a metric down to the labels its queries use
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Recommendation {
+    pub keep: Vec<String>,
+    pub drop: Vec<String>,
+    /// Upper bound on the number of series left after aggregating
+    pub estimated_series: usize,
+    /// Lower bound on the number of series removed by aggregating
+    pub estimated_reduction: usize,
+}
+
+/// Recommend aggregating away the unused labels of a metric, if that would remove any series.
+///
+/// The series left after aggregating can't exceed the current series count, nor the product of the
+/// value counts of the kept labels.
+pub fn recommend(series_count: usize, labels: &[LabelResult]) -> Option<Recommendation> {
+    let (keep, drop): (Vec<&LabelResult>, Vec<&LabelResult>) =
+        labels.iter().partition(|label| label.used);
+
+    if drop.is_empty() {
+        return None;
+    }
+
+    let estimated_series = keep
+        .iter()
+        .try_fold(1usize, |acc, label| acc.checked_mul(label.values_count))
+        .unwrap_or(usize::MAX)
+        .min(series_count);
+
+    let estimated_reduction = series_count - estimated_series;
+
+    if estimated_reduction == 0 {
+        return None;
+    }
+
+    let names = |labels: Vec<&LabelResult>| labels.into_iter().map(|l| l.name.clone()).collect();
+
+    Some(Recommendation {
+        keep: names(keep),
+        drop: names(drop),
+        estimated_series,
+        estimated_reduction,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(name: &str, values_count: usize, used: bool) -> LabelResult {
+        LabelResult {
+            name: name.to_string(),
+            values_count,
+            used,
+        }
+    }
+
+    #[test]
+    fn unused_labels_are_aggregated_away() {
+        let labels = [
+            label("job", 5, true),
+            label("code", 4, true),
+            label("pod", 200, false),
+        ];
+        let recommendation = recommend(4000, &labels).unwrap();
+
+        assert_eq!(recommendation.keep, ["job", "code"]);
+        assert_eq!(recommendation.drop, ["pod"]);
+        assert_eq!(recommendation.estimated_series, 20);
+        assert_eq!(recommendation.estimated_reduction, 3980);
+    }
+
+    #[test]
+    fn estimate_is_bounded_by_the_series_count() {
+        let labels = [label("instance", 1000, true), label("pod", 1000, false)];
+        assert!(recommend(500, &labels).is_none());
+
+        let labels = [label("instance", 400, true), label("pod", 1000, false)];
+        let recommendation = recommend(500, &labels).unwrap();
+        assert_eq!(recommendation.estimated_series, 400);
+        assert_eq!(recommendation.estimated_reduction, 100);
+
+        // Products that overflow are capped too
+        let labels = [
+            label("a", usize::MAX, true),
+            label("b", 2, true),
+            label("c", 2, false),
+        ];
+        assert!(recommend(100, &labels).is_none());
+    }
+
+    #[test]
+    fn nothing_to_drop() {
+        assert!(recommend(100, &[label("job", 5, true)]).is_none());
+        assert!(recommend(100, &[]).is_none());
+
+        // Aggregating everything away leaves a single series
+        let recommendation = recommend(100, &[label("pod", 100, false)]).unwrap();
+        assert!(recommendation.keep.is_empty());
+        assert_eq!(recommendation.estimated_series, 1);
+    }
+}
@@ -1,5 +1,6 @@
-use crate::Args;
+use crate::{Args, ratelimit::RateLimiter};
 use anyhow::Result;
+use regex::Regex;
 use serde::Deserialize;
 use std::path::PathBuf;
 
@@ -13,9 +14,27 @@ impl Config {
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
-    pub grafana: Grafana,
-    pub mimir: Mimir,
+    /// Grafana instances, all contributing to the same usage
+    #[serde(deserialize_with = "deserialize_one_or_many")]
+    pub grafana: Vec<Grafana>,
+    /// Mimir clusters, each with its own tenants
+    #[serde(deserialize_with = "deserialize_one_or_many")]
+    pub mimir: Vec<Mimir>,
     pub http: Http,
+    #[serde(rename = "tenantMapping", default)]
+    pub tenant_mapping: Option<TenantMapping>,
+    #[serde(default)]
+    pub analysis: Analysis,
+    #[serde(rename = "dropRules", default)]
+    pub drop_rules: DropRules,
+    #[serde(default)]
+    pub recommendations: Recommendations,
+    #[serde(default)]
+    pub persistence: Persistence,
+    #[serde(default)]
+    pub history: History,
+    #[serde(default)]
+    pub growth: Growth,
     #[serde(skip)]
     pub output_dir: PathBuf,
     #[serde(skip)]
@@ -24,17 +43,187 @@ pub struct Config {
 
 #[derive(Debug, Clone)]
 pub struct Grafana {
+    /// Name of the instance in logs and metrics. Defaults to the host of the URL.
+    pub name: String,
     pub url: String,
     pub token: String,
+    /// Credentials of a Grafana user, used instead of the token. Needed to discover orgs and to
+    /// switch orgs with the `X-Grafana-Org-Id` header, which service account tokens can't do.
+    pub basic_auth: Option<BasicAuth>,
     pub insecure: bool,
+    /// PEM file of a CA to trust in addition to the system's
+    pub ca_file: Option<PathBuf>,
+    pub analyzer: DashboardAnalyzer,
+    /// Orgs to analyze. Only the token's own org is analyzed if empty and discovery is disabled.
+    pub orgs: Vec<GrafanaOrg>,
+    /// Analyze every org listed by `/api/orgs`. Requires `basic_auth` of a Grafana server admin.
+    pub discover_orgs: bool,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Clone)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct GrafanaOrg {
+    pub id: u64,
+    /// Token of a service account in the org. If unset, the org is selected with the
+    /// `X-Grafana-Org-Id` header using `basic_auth`.
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DashboardAnalyzer {
+    /// Analyze dashboards through the Grafana API
+    #[default]
+    Native,
+    /// Analyze dashboards with the `mimirtool` binary
+    Mimirtool,
+}
+
+#[derive(Debug, Clone)]
 pub struct Mimir {
-    #[serde(rename = "storeGatewayUrl")]
+    /// Name of the cluster in the `mimir_cluster` label and the API. Defaults to the host of the querier URL.
+    pub name: String,
     pub store_gateway_url: String,
-    #[serde(rename = "querierUrl")]
     pub querier_url: String,
+    /// URL of the ruler, used to fetch recording and alerting rules. Rules are not analyzed if unset.
+    pub ruler_url: Option<String>,
+    pub coverage: Coverage,
+    /// Regex matched against the full name of the Grafana datasources querying this cluster
+    pub datasources: Option<Regex>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Coverage {
+    #[serde(default)]
+    pub mode: CoverageMode,
+    /// Maximum number of metrics to analyze per tenant. Defaults to 100 in `top` mode and unlimited in `full` mode.
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Number of metrics to look up per cardinality request in `full` mode
+    #[serde(rename = "batchSize", default = "default_batch_size")]
+    pub batch_size: usize,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CoverageMode {
+    /// Analyze the top metrics by cardinality
+    #[default]
+    Top,
+    /// Analyze every metric name the tenant has
+    Full,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TenantMapping {
+    /// Detect tenants from the `X-Scope-OrgID` header configured on a datasource
+    #[serde(default = "default_true")]
+    pub detect: bool,
+    /// Explicit datasource to tenant mappings, checked in order before detection
+    #[serde(default)]
+    pub datasources: Vec<DatasourceMapping>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DatasourceMapping {
+    /// Datasource UID to match exactly
+    #[serde(default)]
+    pub uid: Option<String>,
+    /// Regex to match against the full datasource name
+    #[serde(default, deserialize_with = "deserialize_anchored_regex")]
+    pub name: Option<Regex>,
+    /// Tenant ID, or several separated by `|` for federated datasources
+    pub tenant: String,
+    /// The cluster the datasource queries, taking precedence over the clusters' `datasources`
+    #[serde(default)]
+    pub cluster: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Analysis {
+    /// Number of tenants analyzed concurrently
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Seconds after which the analysis of a single tenant is abandoned
+    #[serde(rename = "tenantTimeout", default = "default_tenant_timeout")]
+    pub tenant_timeout: u64,
+    #[serde(rename = "rateLimits", default)]
+    pub rate_limits: RateLimits,
+}
+
+/// Maximum requests per second to each external target. Targets without a limit are unlimited.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RateLimits {
+    #[serde(rename = "storeGateway", default)]
+    pub store_gateway: Option<f64>,
+    #[serde(default)]
+    pub querier: Option<f64>,
+    #[serde(default)]
+    pub ruler: Option<f64>,
+    #[serde(default)]
+    pub grafana: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct DropRules {
+    /// Write the drop rules to the output directory after each cycle
+    #[serde(default)]
+    pub write: bool,
+    /// Regexes matching the full name of metrics that are never dropped
+    #[serde(default, deserialize_with = "deserialize_anchored_regexes")]
+    pub exemptions: Vec<Regex>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Recommendations {
+    /// Analyze the labels of used metrics to detect unused labels and recommend aggregations
+    #[serde(default)]
+    pub enabled: bool,
+    /// Only analyze metrics with at least this many series
+    #[serde(rename = "minSeries", default = "default_min_series")]
+    pub min_series: usize,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Persistence {
+    /// Save the latest results after each cycle, and load them on startup
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// File to save the results to. Defaults to `results.json` in the output directory.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Seconds to keep exporting metrics and tenants that disappeared between cycles
+    #[serde(rename = "staleRetention", default)]
+    pub stale_retention: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct History {
+    /// Record the status of each metric per cycle in `history.db` in the output directory
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Seconds after a metric is first seen during which it isn't flagged as unused
+    #[serde(rename = "gracePeriod", default)]
+    pub grace_period: u64,
+    /// Seconds to keep the status of past cycles, and metrics that are no longer seen
+    #[serde(default = "default_history_retention")]
+    pub retention: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Growth {
+    /// Flag metrics whose series count grew by at least this ratio over a day or a week. Requires
+    /// history, and is disabled if unset.
+    #[serde(rename = "explodingRatio", default)]
+    pub exploding_ratio: Option<f64>,
+    /// Only flag metrics with at least this many series
+    #[serde(rename = "minSeries", default = "default_min_series")]
+    pub min_series: usize,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -46,12 +235,130 @@ pub struct Http {
 impl Config {
     /// Create a new Config instance from a file, merging with CLI args
     pub fn new(cli: Args) -> Result<Self> {
-        let mut config = Self::from_file(&cli.config)?;
+        let path = cli
+            .config
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("A config file is required, set it with --config"))?;
+
+        let mut config = Self::from_file(path)?.with_output_dir(cli.output_dir.clone());
+
+        if let Some(stale_retention) = cli.stale_retention {
+            config.persistence.stale_retention = stale_retention;
+        }
+
         config.cli = cli;
+        config.validate()?;
 
         Ok(config)
     }
 
+    /// Check settings that depend on each other
+    fn validate(&self) -> Result<()> {
+        if self.grafana.is_empty() {
+            anyhow::bail!("At least one Grafana instance is required");
+        }
+
+        if self.grafana.len() > 1
+            && self
+                .grafana
+                .iter()
+                .any(|g| g.analyzer == DashboardAnalyzer::Mimirtool)
+        {
+            anyhow::bail!("The mimirtool analyzer only supports a single Grafana instance");
+        }
+
+        for grafana in self.grafana.iter().filter(|g| g.basic_auth.is_none()) {
+            if grafana.discover_orgs {
+                anyhow::bail!(
+                    "Grafana instance '{}' needs basicAuth to discover orgs, since service account \
+                     tokens can't list orgs",
+                    grafana.name
+                );
+            }
+
+            if let Some(org) = grafana.orgs.iter().find(|org| org.token.is_none()) {
+                anyhow::bail!(
+                    "Org {} of Grafana instance '{}' needs its own token, or basicAuth to switch \
+                     to it",
+                    org.id,
+                    grafana.name
+                );
+            }
+        }
+
+        let mut names = std::collections::HashSet::new();
+
+        if let Some(duplicate) = self.grafana.iter().find(|g| !names.insert(&g.name)) {
+            anyhow::bail!(
+                "Grafana instance '{}' is configured more than once, give each a unique name",
+                duplicate.name
+            );
+        }
+
+        if self.mimir.is_empty() {
+            anyhow::bail!("At least one Mimir cluster is required");
+        }
+
+        let mut names = std::collections::HashSet::new();
+
+        if let Some(duplicate) = self.mimir.iter().find(|m| !names.insert(&m.name)) {
+            anyhow::bail!(
+                "Mimir cluster '{}' is configured more than once, give each a unique name",
+                duplicate.name
+            );
+        }
+
+        if let Some(mimir) = self
+            .mimir
+            .iter()
+            .find(|m| !(1..=Coverage::MAX_BATCH_SIZE).contains(&m.coverage.batch_size))
+        {
+            anyhow::bail!(
+                "The coverage batchSize of Mimir cluster '{}' must be between 1 and {}",
+                mimir.name,
+                Coverage::MAX_BATCH_SIZE
+            );
+        }
+
+        for (target, limit) in self.analysis.rate_limits.limits() {
+            if let Some(limit) = limit
+                && let Err(e) = RateLimiter::new(limit)
+            {
+                anyhow::bail!("The {} rate limit is invalid: {}", target, e);
+            }
+        }
+
+        let rules = self.tenant_mapping.iter().flat_map(|m| &m.datasources);
+
+        if let Some(cluster) = rules
+            .filter_map(|rule| rule.cluster.as_ref())
+            .find(|cluster| !names.contains(cluster))
+        {
+            anyhow::bail!(
+                "Tenant mapping refers to unknown Mimir cluster '{}'",
+                cluster
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The dashboard analyzer, which is the same for every Grafana instance
+    pub fn dashboard_analyzer(&self) -> DashboardAnalyzer {
+        self.grafana.first().map(|g| g.analyzer).unwrap_or_default()
+    }
+
+    /// Path of the file the latest results are saved to, if persistence is enabled
+    pub fn results_path(&self) -> Option<PathBuf> {
+        match &self.persistence {
+            Persistence { enabled: false, .. } => None,
+            Persistence {
+                path: Some(path), ..
+            } => Some(path.clone()),
+            Persistence { path: None, .. } => Some(self.output_dir.join("results.json")),
+        }
+    }
+
     /// Load config from a file
     fn from_file(path: &PathBuf) -> Result<Self> {
         tracing::info!("Loading config from file");
@@ -61,6 +368,176 @@ impl Config {
     }
 }
 
+impl Default for Coverage {
+    fn default() -> Self {
+        Self {
+            mode: CoverageMode::default(),
+            limit: None,
+            batch_size: default_batch_size(),
+        }
+    }
+}
+
+impl Default for Analysis {
+    fn default() -> Self {
+        Self {
+            concurrency: default_concurrency(),
+            tenant_timeout: default_tenant_timeout(),
+            rate_limits: RateLimits::default(),
+        }
+    }
+}
+
+impl Default for Recommendations {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_series: default_min_series(),
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            grace_period: 0,
+            retention: default_history_retention(),
+        }
+    }
+}
+
+impl Default for Growth {
+    fn default() -> Self {
+        Self {
+            exploding_ratio: None,
+            min_series: default_min_series(),
+        }
+    }
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: None,
+            stale_retention: 0,
+        }
+    }
+}
+
+impl RateLimits {
+    /// The limit of each target, by its name in the config file
+    fn limits(&self) -> [(&'static str, Option<f64>); 4] {
+        [
+            ("storeGateway", self.store_gateway),
+            ("querier", self.querier),
+            ("ruler", self.ruler),
+            ("grafana", self.grafana),
+        ]
+    }
+}
+
+impl Coverage {
+    /// Default number of metrics analyzed in `top` mode
+    pub const DEFAULT_TOP_LIMIT: usize = 100;
+
+    /// Maximum number of metrics per request to the cardinality API
+    pub const MAX_BATCH_SIZE: usize = 500;
+}
+
+impl Mimir {
+    /// Check whether a Grafana datasource is known to query this cluster
+    pub fn queried_by(&self, datasource: &str) -> bool {
+        self.datasources
+            .as_ref()
+            .is_some_and(|re| re.is_match(datasource))
+    }
+}
+
+impl DatasourceMapping {
+    /// Check whether this mapping applies to a datasource
+    pub fn matches(&self, uid: &str, name: &str) -> bool {
+        self.uid.as_deref() == Some(uid) || self.name.as_ref().is_some_and(|re| re.is_match(name))
+    }
+
+    /// Get the tenants this mapping resolves to
+    pub fn tenants(&self) -> Vec<String> {
+        self.tenant.split('|').map(String::from).collect()
+    }
+}
+
+/// Deserialize an optional regex, anchored to match the full string
+fn deserialize_anchored_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|pattern| Regex::new(&format!("^(?:{})$", pattern)))
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Deserialize either a single map or a list of maps, keeping the errors of the value itself
+fn deserialize_one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    struct OneOrMany<T>(std::marker::PhantomData<T>);
+
+    impl<'de, T: Deserialize<'de>> serde::de::Visitor<'de> for OneOrMany<T> {
+        type Value = Vec<T>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a map or a list of maps")
+        }
+
+        fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+            T::deserialize(serde::de::value::MapAccessDeserializer::new(map))
+                .map(|value| vec![value])
+        }
+
+        fn visit_seq<A: serde::de::SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+            Vec::deserialize(serde::de::value::SeqAccessDeserializer::new(seq))
+        }
+    }
+
+    deserializer.deserialize_any(OneOrMany(std::marker::PhantomData))
+}
+
+/// Deserialize a list of regexes, each anchored to match the full string
+fn deserialize_anchored_regexes<'de, D>(deserializer: D) -> Result<Vec<Regex>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|pattern| Regex::new(&format!("^(?:{})$", pattern)))
+        .collect::<Result<_, _>>()
+        .map_err(serde::de::Error::custom)
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_batch_size() -> usize {
+    200
+}
+
+fn default_min_series() -> usize {
+    1000
+}
+
+fn default_concurrency() -> usize {
+    4
+}
+
+fn default_tenant_timeout() -> u64 {
+    1800
+}
+
 impl Grafana {
     /// Create a new Grafana instance, resolving token from environment variable if needed
     pub fn new(
@@ -68,21 +545,85 @@ impl Grafana {
         token: Option<String>,
         token_from: Option<String>,
         insecure: bool,
+        analyzer: DashboardAnalyzer,
     ) -> anyhow::Result<Self> {
-        let token = if token.is_none() && token_from.is_some() {
-            std::env::var(token_from.unwrap())?
-        } else {
-            token.unwrap_or_default()
-        };
+        let token = resolve_token(token, token_from)?.unwrap_or_default();
+
+        let name = reqwest::Url::parse(&url)
+            .ok()
+            .and_then(|url| url.host_str().map(String::from))
+            .unwrap_or_else(|| url.clone());
 
         Ok(Self {
+            name,
             url,
             token,
+            basic_auth: None,
             insecure,
+            ca_file: None,
+            analyzer,
+            orgs: Vec::new(),
+            discover_orgs: false,
         })
     }
 }
 
+/// Resolve a token set directly, or read from the environment variable named by `token_from`
+fn resolve_token(
+    token: Option<String>,
+    token_from: Option<String>,
+) -> anyhow::Result<Option<String>> {
+    match (token, token_from) {
+        (None, Some(token_from)) => Ok(Some(std::env::var(token_from)?)),
+        (token, _) => Ok(token),
+    }
+}
+
+impl<'de> Deserialize<'de> for BasicAuth {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct BasicAuthRaw {
+            username: String,
+            password: Option<String>,
+            #[serde(rename = "passwordFrom")]
+            password_from: Option<String>,
+        }
+
+        let raw = BasicAuthRaw::deserialize(deserializer)?;
+        let password = resolve_token(raw.password, raw.password_from)
+            .map_err(serde::de::Error::custom)?
+            .unwrap_or_default();
+
+        Ok(BasicAuth {
+            username: raw.username,
+            password,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for GrafanaOrg {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct GrafanaOrgRaw {
+            id: u64,
+            token: Option<String>,
+            #[serde(rename = "tokenFrom")]
+            token_from: Option<String>,
+        }
+
+        let raw = GrafanaOrgRaw::deserialize(deserializer)?;
+        let token = resolve_token(raw.token, raw.token_from).map_err(serde::de::Error::custom)?;
+
+        Ok(GrafanaOrg { id: raw.id, token })
+    }
+}
+
 impl<'de> Deserialize<'de> for Grafana {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -90,21 +631,89 @@ impl<'de> Deserialize<'de> for Grafana {
     {
         #[derive(Deserialize)]
         struct GrafanaRaw {
+            name: Option<String>,
             url: String,
             token: Option<String>,
             #[serde(rename = "tokenFrom")]
             token_from: Option<String>,
             #[serde(default)]
             insecure: Option<bool>,
+            #[serde(default)]
+            analyzer: DashboardAnalyzer,
+            #[serde(default)]
+            orgs: Vec<GrafanaOrg>,
+            #[serde(rename = "discoverOrgs", default)]
+            discover_orgs: bool,
+            #[serde(rename = "basicAuth", default)]
+            basic_auth: Option<BasicAuth>,
+            #[serde(rename = "caFile", default)]
+            ca_file: Option<PathBuf>,
         }
 
         let raw = GrafanaRaw::deserialize(deserializer)?;
-        Grafana::new(
+        let mut grafana = Grafana::new(
             raw.url,
             raw.token,
             raw.token_from,
             raw.insecure.unwrap_or(false),
+            raw.analyzer,
         )
-        .map_err(serde::de::Error::custom)
+        .map_err(serde::de::Error::custom)?;
+
+        grafana.orgs = raw.orgs;
+        grafana.discover_orgs = raw.discover_orgs;
+        grafana.basic_auth = raw.basic_auth;
+        grafana.ca_file = raw.ca_file;
+
+        if let Some(name) = raw.name {
+            grafana.name = name;
+        }
+
+        Ok(grafana)
+    }
+}
+
+fn default_history_retention() -> u64 {
+    // 90 days
+    7_776_000
+}
+
+impl<'de> Deserialize<'de> for Mimir {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct MimirRaw {
+            name: Option<String>,
+            #[serde(rename = "storeGatewayUrl")]
+            store_gateway_url: String,
+            #[serde(rename = "querierUrl")]
+            querier_url: String,
+            #[serde(rename = "rulerUrl", default)]
+            ruler_url: Option<String>,
+            #[serde(default)]
+            coverage: Coverage,
+            #[serde(default, deserialize_with = "deserialize_anchored_regex")]
+            datasources: Option<Regex>,
+        }
+
+        let raw = MimirRaw::deserialize(deserializer)?;
+
+        let name = raw.name.unwrap_or_else(|| {
+            reqwest::Url::parse(&raw.querier_url)
+                .ok()
+                .and_then(|url| url.host_str().map(String::from))
+                .unwrap_or_else(|| raw.querier_url.clone())
+        });
+
+        Ok(Mimir {
+            name,
+            store_gateway_url: raw.store_gateway_url,
+            querier_url: raw.querier_url,
+            ruler_url: raw.ruler_url,
+            coverage: raw.coverage,
+            datasources: raw.datasources,
+        })
     }
 }
@@ -0,0 +1,299 @@
+use crate::{
+    config::DropRules,
+    mimir::tenant::Tenant,
+    results::{MetricStatus, ResultStore},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    path::Path,
+};
+
+/// Number of metric names matched by a single relabel rule, to keep each regex readable
+const METRICS_PER_RULE: usize = 100;
+
+/// A format drop rules can be generated in
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Alloy `prometheus.relabel` components, one per tenant
+    Alloy,
+    /// Mimir runtime-config overrides with per-tenant `metric_relabel_configs`, for a single
+    /// cluster
+    Mimir,
+}
+
+/// Unused metrics to drop, per tenant
+pub type UnusedMetrics = BTreeMap<Tenant, Vec<String>>;
+
+#[derive(Serialize, Debug)]
+struct RuntimeConfig {
+    overrides: BTreeMap<String, Overrides>,
+}
+
+#[derive(Serialize, Debug)]
+struct Overrides {
+    metric_relabel_configs: Vec<RelabelConfig>,
+}
+
+#[derive(Serialize, Debug)]
+struct RelabelConfig {
+    source_labels: Vec<String>,
+    regex: String,
+    action: String,
+}
+
+/// Collect the unused metrics of each tenant, leaving out exempted metrics
+pub fn unused_metrics(store: &ResultStore, config: &DropRules) -> UnusedMetrics {
+    let mut unused = UnusedMetrics::new();
+
+    store.visit(|view| {
+        let metrics: Vec<String> = view
+            .result
+            .metrics
+            .iter()
+            .filter(|(_, result)| result.status() == MetricStatus::Unused)
+            .map(|(metric, _)| metric)
+            .filter(|metric| !config.exemptions.iter().any(|re| re.is_match(metric)))
+            .cloned()
+            .collect();
+
+        if !metrics.is_empty() {
+            unused.insert(view.tenant.clone(), metrics);
+        }
+    });
+
+    unused
+}
+
+/// Get the clusters with unused metrics
+pub fn clusters(unused: &UnusedMetrics) -> BTreeSet<&str> {
+    unused
+        .keys()
+        .map(|tenant| tenant.cluster.as_str())
+        .collect()
+}
+
+/// Render drop rules for the unused metrics in the given format. Mimir drop rules can only be
+/// rendered for the tenants of a single cluster.
+pub fn render(format: Format, unused: &UnusedMetrics) -> anyhow::Result<String> {
+    match format {
+        Format::Alloy => Ok(render_alloy(unused)),
+        Format::Mimir => render_mimir(unused),
+    }
+}
+
+/// Write drop rules to the output directory: Alloy components for every cluster in
+/// `drop-rules.alloy`, and the Mimir runtime config of each cluster in
+/// `drop-rules-runtime-config-<cluster>.yaml`
+pub fn write(output_dir: &Path, clusters: &[&str], unused: &UnusedMetrics) -> anyhow::Result<()> {
+    std::fs::write(output_dir.join("drop-rules.alloy"), render_alloy(unused))?;
+
+    for cluster in clusters {
+        let mut cluster_unused = unused.clone();
+        cluster_unused.retain(|tenant, _| tenant.cluster == *cluster);
+
+        std::fs::write(
+            output_dir.join(format!("drop-rules-runtime-config-{}.yaml", cluster)),
+            render_mimir(&cluster_unused)?,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn render_alloy(unused: &UnusedMetrics) -> String {
+    let mut output = String::new();
+
+    for (tenant, metrics) in unused {
+        let _ = writeln!(
+            output,
+            "// Unused metrics in tenant '{}' of cluster '{}'",
+            tenant.id, tenant.cluster
+        );
+        let _ = writeln!(
+            output,
+            "prometheus.relabel \"drop_unused_{}_{}\" {{",
+            component_label(&tenant.cluster),
+            component_label(&tenant.id)
+        );
+        let _ = writeln!(output, "  // Replace with the receivers to forward to");
+        let _ = writeln!(output, "  forward_to = []");
+
+        for regex in regexes(metrics) {
+            let _ = writeln!(output);
+            let _ = writeln!(output, "  rule {{");
+            let _ = writeln!(output, "    source_labels = [\"__name__\"]");
+            let _ = writeln!(output, "    regex         = \"{}\"", escape_string(&regex));
+            let _ = writeln!(output, "    action        = \"drop\"");
+            let _ = writeln!(output, "  }}");
+        }
+
+        let _ = writeln!(output, "}}");
+        let _ = writeln!(output);
+    }
+
+    output
+}
+
+fn render_mimir(unused: &UnusedMetrics) -> anyhow::Result<String> {
+    if clusters(unused).len() > 1 {
+        anyhow::bail!("Mimir drop rules can only be rendered for a single cluster");
+    }
+
+    let overrides = unused
+        .iter()
+        .map(|(tenant, metrics)| {
+            let metric_relabel_configs = regexes(metrics)
+                .map(|regex| RelabelConfig {
+                    source_labels: vec!["__name__".to_string()],
+                    regex,
+                    action: "drop".to_string(),
+                })
+                .collect();
+
+            (
+                tenant.id.clone(),
+                Overrides {
+                    metric_relabel_configs,
+                },
+            )
+        })
+        .collect();
+
+    Ok(serde_norway::to_string(&RuntimeConfig { overrides })?)
+}
+
+/// Build the regexes matching a list of metrics, in chunks
+fn regexes(metrics: &[String]) -> impl Iterator<Item = String> {
+    metrics.chunks(METRICS_PER_RULE).map(|chunk| {
+        chunk
+            .iter()
+            .map(|metric| regex::escape(metric))
+            .collect::<Vec<_>>()
+            .join("|")
+    })
+}
+
+/// Turn a cluster name or tenant ID into part of a valid Alloy component label
+fn component_label(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c,
+            false => '_',
+        })
+        .collect()
+}
+
+/// Escape a string for an Alloy string literal
+fn escape_string(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::results::{MetricResult, SeriesGrowth, TenantResult};
+    use std::collections::HashMap;
+
+    fn unused(entries: &[(&str, &str, &[&str])]) -> UnusedMetrics {
+        entries
+            .iter()
+            .map(|(cluster, tenant, metrics)| {
+                let metrics = metrics.iter().map(|m| m.to_string()).collect();
+                (Tenant::new(*cluster, *tenant), metrics)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn alloy_rules_escape_names() {
+        let rules = render(
+            Format::Alloy,
+            &unused(&[("us-east", "team/a", &["http.server.duration", "up"])]),
+        )
+        .unwrap();
+
+        assert!(rules.contains(r#"prometheus.relabel "drop_unused_us_east_team_a" {"#));
+        assert!(rules.contains(r#"regex         = "http\\.server\\.duration|up""#));
+        assert!(rules.contains("// Unused metrics in tenant 'team/a' of cluster 'us-east'"));
+    }
+
+    #[test]
+    fn mimir_rules_are_rendered_per_tenant() {
+        let rules = render(
+            Format::Mimir,
+            &unused(&[("main", "prod", &["a", "b"]), ("main", "dev", &["c"])]),
+        )
+        .unwrap();
+        let config: serde_json::Value = serde_norway::from_str(&rules).unwrap();
+
+        assert_eq!(
+            config["overrides"]["prod"]["metric_relabel_configs"][0]["regex"],
+            "a|b"
+        );
+        assert_eq!(
+            config["overrides"]["dev"]["metric_relabel_configs"][0]["action"],
+            "drop"
+        );
+
+        let several = unused(&[("us", "prod", &["a"]), ("eu", "prod", &["a"])]);
+        assert!(render(Format::Mimir, &several).is_err());
+    }
+
+    #[test]
+    fn rules_are_chunked() {
+        let metrics: Vec<String> = (0..250).map(|i| format!("metric_{}", i)).collect();
+        let metrics: Vec<&str> = metrics.iter().map(String::as_str).collect();
+        let rules = render(Format::Alloy, &unused(&[("main", "prod", &metrics)])).unwrap();
+
+        assert_eq!(rules.matches("rule {").count(), 3);
+        assert!(rules.contains(r#""metric_0|metric_1|"#));
+        assert!(rules.contains(r#"|metric_99""#));
+        assert!(rules.contains(r#""metric_100|"#));
+    }
+
+    #[test]
+    fn exempted_and_used_metrics_are_kept() {
+        let store = ResultStore::default();
+        let tenant = Tenant::new("main", "prod");
+        let metric = |used: bool| MetricResult {
+            series_count: 10,
+            usage: used.then_some(crate::usage::Usage::Direct),
+            sources: Vec::new(),
+            labels: Vec::new(),
+            recommendation: None,
+            first_seen: None,
+            unused_since: None,
+            in_grace_period: false,
+            usage_unknown: false,
+            growth: SeriesGrowth::default(),
+            exploding: false,
+        };
+        let result = TenantResult {
+            metrics: BTreeMap::from([
+                ("go_goroutines".to_string(), metric(false)),
+                ("go_goroutines_total".to_string(), metric(false)),
+                ("http_requests_total".to_string(), metric(true)),
+                ("node_load1".to_string(), metric(false)),
+            ]),
+            ..TenantResult::new()
+        };
+        store.replace(
+            "main",
+            std::slice::from_ref(&tenant),
+            HashMap::from([(tenant.clone(), result)]),
+            chrono::Duration::zero(),
+        );
+
+        // Exemptions match the full name
+        let config: DropRules = serde_norway::from_str("exemptions: [go_goroutines]").unwrap();
+
+        assert_eq!(
+            unused_metrics(&store, &config)[&tenant],
+            ["go_goroutines_total", "node_load1"]
+        );
+    }
+}
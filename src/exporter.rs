@@ -1,67 +1,390 @@
 use crate::{
-    config::Config,
-    grafana::{Grafana, alert::Alert},
+    aggregation::{self, LabelResult},
+    config::{Config, Coverage, CoverageMode, DashboardAnalyzer},
+    drop_rules,
+    grafana::Grafana,
+    history::HistoryStore,
+    jobs::{Batch, JOBS},
     metrics::{self, Status, analysis::TaskFailure},
-    mimir::Mimir,
+    mimir::{Mimir, cardinality::Cardinality, tenant::Tenant},
+    promql::{MetricReferences, labels::LabelUsage},
+    ratelimit::RateLimiters,
+    results::{MetricResult, RESULTS, SeriesGrowth, TenantResult},
+    usage::{RuleUsage, Source, TenantUsage, Usage, UsageGraph},
 };
-use std::time::Duration;
+use futures_util::stream::{self, StreamExt};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+/// Number of metrics of a tenant whose labels are fetched concurrently
+const LABEL_REQUESTS_CONCURRENCY: usize = 8;
 
 pub struct Exporter {
     config: Config,
-    grafana: Grafana,
-    mimir: Mimir,
+    grafanas: Vec<Grafana>,
+    clusters: Vec<Mimir>,
+    history: Option<HistoryStore>,
 }
 
 impl Exporter {
     /// Create a new Exporter instance
     pub fn new(config: Config) -> anyhow::Result<Self> {
-        let grafana = Grafana::new(config.grafana.clone())?;
-        let mimir = Mimir::new(config.clone());
+        let limits = RateLimiters::new(&config.analysis.rate_limits)?;
+        let grafanas = config
+            .grafana
+            .iter()
+            .map(|grafana| Grafana::new(grafana.clone(), limits.clone()))
+            .collect::<anyhow::Result<_>>()?;
+        let clusters = config
+            .mimir
+            .iter()
+            .map(|cluster| {
+                let limits = limits.for_cluster(&config.analysis.rate_limits)?;
+                Ok(Mimir::new(config.clone(), cluster.clone(), limits))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let history = match config.history.enabled {
+            true => Some(HistoryStore::open(
+                &config.output_dir.join("history.db"),
+                &config.history,
+                &config.growth,
+            )?),
+            false => None,
+        };
 
         Ok(Self {
             config,
-            grafana,
-            mimir,
+            grafanas,
+            clusters,
+            history,
         })
     }
 
     /// Start the exporter loop
-    pub async fn start(&self) -> anyhow::Result<()> {
+    pub async fn start(self: Arc<Self>) -> anyhow::Result<()> {
         tracing::info!("Starting exporter");
 
+        // Export the results saved before the last restart until the first cycle completes
+        if let Some(path) = self.config.results_path() {
+            match RESULTS.load(&path) {
+                Ok(count) => tracing::info!("Loaded saved results of {} tenants", count),
+                Err(e) => tracing::error!("Failed to load saved results: {}", e),
+            }
+
+            let clusters: Vec<&str> = self.clusters.iter().map(|c| c.name()).collect();
+            RESULTS.retain_clusters(&clusters);
+        }
+
+        let mut next = tokio::time::Instant::now();
+
         loop {
-            if let Err(e) = self.analyze().await {
-                tracing::error!("Analysis failed: {}", e);
-                metrics::analysis::record_analysis_cycle(Status::Failure);
-                tokio::time::sleep(Duration::from_secs(120)).await;
+            // Wait for the next scheduled cycle, or an analysis requested over HTTP
+            let scheduled = tokio::select! {
+                _ = tokio::time::sleep_until(next) => true,
+                _ = JOBS.wait() => false,
+            };
+
+            // Queued jobs are run by the scheduled cycle too
+            let batch = JOBS.start(scheduled);
+
+            if !scheduled && batch.is_empty() {
                 continue;
             }
 
-            metrics::analysis::record_analysis_cycle(Status::Success);
-            tokio::time::sleep(Duration::from_secs(self.config.cli.interval)).await;
+            let result = self.run_once(&batch).await;
+
+            if scheduled {
+                let delay = match result {
+                    Ok(_) => self.config.cli.interval,
+                    Err(_) => 120,
+                };
+
+                next = tokio::time::Instant::now() + Duration::from_secs(delay);
+            }
+        }
+    }
+
+    /// Run a single analysis cycle for the tenants of a batch, returning the tenants that failed
+    /// to be analyzed. Only cycles covering every tenant are counted in the cycle metrics.
+    pub async fn run_once(self: &Arc<Self>, batch: &Batch) -> anyhow::Result<Vec<Tenant>> {
+        let result = self.analyze(batch).await;
+
+        if let Err(e) = &result {
+            tracing::error!("Analysis failed: {}", e);
+        }
+
+        if batch.is_full() {
+            let status = match &result {
+                Ok(_) => Status::Success,
+                Err(_) => Status::Failure,
+            };
+
+            metrics::analysis::record_analysis_cycle(status);
         }
+
+        JOBS.finish(batch, result.as_ref().err());
+
+        result
     }
 
     /// Perform analysis
     #[tracing::instrument(skip(self))]
-    async fn analyze(&self) -> anyhow::Result<()> {
-        // Fetch tenants
-        let tenants = self.mimir.get_tenants().await?;
-        tracing::info!("Fetched {} tenants", tenants.len());
+    async fn analyze(self: &Arc<Self>, batch: &Batch) -> anyhow::Result<Vec<Tenant>> {
+        // Fetch the tenants of every cluster. Clusters whose tenants can't be fetched keep their
+        // previous results.
+        let mut tenants = Vec::new();
+        let mut discovered = Vec::new();
+        let mut undiscovered = Vec::new();
+
+        for mimir in &self.clusters {
+            match mimir.get_tenants().await {
+                Ok(found) => {
+                    tracing::info!(
+                        "Fetched {} tenants of cluster '{}'",
+                        found.len(),
+                        mimir.name()
+                    );
+                    tenants.extend(found);
+                    discovered.push(mimir.name());
+                }
+                Err(e) => {
+                    tracing::error!(
+                        "Failed to fetch tenants of cluster '{}': {}",
+                        mimir.name(),
+                        e
+                    );
+                    undiscovered.push(mimir.name());
+                }
+            }
+        }
 
-        // Analyze Grafana dashboards
-        self.mimir.analyze_grafana().await?;
+        if discovered.is_empty() {
+            anyhow::bail!(
+                "Failed to fetch tenants of clusters: {}",
+                undiscovered.join(", ")
+            );
+        }
 
-        // Get alert rules
-        let alerts = self.grafana.get_alert_rules().await?;
+        JOBS.discovered(batch, &discovered, &tenants);
 
-        // Analyze each tenant
-        for tenant in tenants {
-            if let Err(e) = self.process_tenant(&tenant, &alerts).await {
-                tracing::error!("Failed to analyze tenant '{}': {}", tenant, e);
-                metrics::analysis::record_analysis_error(TaskFailure::Tenant(tenant.clone()));
+        // Analyze Grafana dashboards and alert rules
+        let (mut dashboard_usage, alert_usage) = self.analyze_grafana(&tenants).await;
 
-                continue;
+        if self.config.dashboard_analyzer() == DashboardAnalyzer::Mimirtool
+            && let Err(e) = self.clusters[0].analyze_grafana().await
+        {
+            tracing::error!("Failed to analyze dashboards with mimirtool: {}", e);
+            dashboard_usage.fail(format!("mimirtool: {}", e));
+        }
+
+        // Analyze tenants concurrently, bounded by the configured concurrency
+        let dashboard_usage = Arc::new(dashboard_usage);
+        let alert_usage = Arc::new(alert_usage);
+        let permits = Arc::new(Semaphore::new(self.config.analysis.concurrency.max(1)));
+        let timeout = Duration::from_secs(self.config.analysis.tenant_timeout);
+        let mut tasks = JoinSet::new();
+        // The tenant of each task, to tell which one panicked
+        let mut task_tenants = HashMap::new();
+
+        for tenant in tenants.iter().filter(|tenant| batch.includes(tenant)) {
+            let exporter = Arc::clone(self);
+            let tenant = tenant.clone();
+            let dashboard_usage = Arc::clone(&dashboard_usage);
+            let alert_usage = Arc::clone(&alert_usage);
+            let permits = Arc::clone(&permits);
+
+            let task_tenant = tenant.clone();
+
+            let task = tasks.spawn(async move {
+                let _permit = permits
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore is never closed");
+
+                let mimir = exporter.cluster(&tenant.cluster);
+                let analysis =
+                    exporter.process_tenant(mimir, &tenant, &dashboard_usage, &alert_usage);
+
+                let result = match tokio::time::timeout(timeout, analysis).await {
+                    Ok(result) => result?,
+                    Err(_) => anyhow::bail!("Timed out after {}s", timeout.as_secs()),
+                };
+
+                Arc::clone(&exporter).record_history(tenant, result).await
+            });
+
+            task_tenants.insert(task.id(), task_tenant);
+        }
+
+        let mut results: HashMap<&str, HashMap<Tenant, TenantResult>> = HashMap::new();
+        let mut failed = Vec::new();
+
+        while let Some(task) = tasks.join_next_with_id().await {
+            let (tenant, result) = match task {
+                Ok((id, result)) => (task_tenants[&id].clone(), result),
+                Err(e) => (
+                    task_tenants[&e.id()].clone(),
+                    Err(anyhow::anyhow!("Analysis task failed: {}", e)),
+                ),
+            };
+
+            JOBS.tenant_finished(batch, &tenant, result.is_ok());
+
+            match result {
+                Ok(result) => {
+                    let cluster = self.cluster(&tenant.cluster).name();
+                    results.entry(cluster).or_default().insert(tenant, result);
+                }
+                Err(e) => {
+                    tracing::error!("Failed to analyze tenant '{}': {}", tenant, e);
+                    metrics::analysis::record_analysis_error(TaskFailure::Tenant(tenant.clone()));
+                    failed.push(tenant);
+                }
+            }
+        }
+
+        // Replace the previous cycle's results of each cluster
+        let retention = chrono::Duration::seconds(self.config.persistence.stale_retention as i64);
+
+        for cluster in discovered {
+            let cluster_tenants: Vec<Tenant> = tenants
+                .iter()
+                .filter(|tenant| tenant.cluster == cluster)
+                .cloned()
+                .collect();
+            let cluster_results = results.remove(cluster).unwrap_or_default();
+
+            RESULTS.replace(cluster, &cluster_tenants, cluster_results, retention);
+        }
+
+        // Save the results so they survive restarts
+        if let Some(path) = self.config.results_path()
+            && let Err(e) = RESULTS.save(&path)
+        {
+            tracing::error!("Failed to save results: {}", e);
+        }
+
+        // Write drop rules for the unused metrics, once every tenant has been analyzed
+        if self.config.drop_rules.write && batch.is_full() {
+            let unused = drop_rules::unused_metrics(&RESULTS, &self.config.drop_rules);
+
+            let clusters: Vec<&str> = self.clusters.iter().map(|c| c.name()).collect();
+
+            if let Err(e) = drop_rules::write(&self.config.output_dir, &clusters, &unused) {
+                tracing::error!("Failed to write drop rules: {}", e);
+            }
+        }
+
+        if !undiscovered.is_empty() {
+            anyhow::bail!(
+                "Failed to fetch tenants of clusters: {}",
+                undiscovered.join(", ")
+            );
+        }
+
+        Ok(failed)
+    }
+
+    /// Get the client of a configured cluster
+    fn cluster(&self, name: &str) -> &Mimir {
+        self.clusters
+            .iter()
+            .find(|mimir| mimir.name() == name)
+            .expect("tenants are only discovered in configured clusters")
+    }
+
+    /// Record a tenant's result in the history, if enabled. SQLite blocks, so this runs on a
+    /// blocking thread.
+    async fn record_history(
+        self: Arc<Self>,
+        tenant: Tenant,
+        mut result: TenantResult,
+    ) -> anyhow::Result<TenantResult> {
+        if self.history.is_none() {
+            return Ok(result);
+        }
+
+        let recorded = tokio::task::spawn_blocking(move || {
+            if let Some(history) = &self.history
+                && let Err(e) = history.record(&tenant, &mut result)
+            {
+                tracing::error!("Failed to record history of tenant '{}': {}", tenant, e);
+            }
+
+            result
+        });
+
+        Ok(recorded.await?)
+    }
+
+    /// Analyze the dashboards and alert rules of every Grafana instance, returning the metrics
+    /// they reference per tenant.
+    ///
+    /// Instances, or parts of them, that can't be analyzed are recorded as failures in the usage,
+    /// so that metrics only they reference aren't reported as unused.
+    async fn analyze_grafana(&self, tenants: &[Tenant]) -> (TenantUsage, TenantUsage) {
+        let mut dashboard_usage = TenantUsage::default();
+        let mut alert_usage = TenantUsage::default();
+
+        for grafana in &self.grafanas {
+            let failures = dashboard_usage.failures().len() + alert_usage.failures().len();
+
+            let result = self
+                .analyze_grafana_instance(grafana, tenants, &mut dashboard_usage, &mut alert_usage)
+                .await;
+
+            if let Err(e) = result {
+                tracing::error!("Failed to analyze {}: {}", grafana.describe(), e);
+                dashboard_usage.fail(format!("{}: {}", grafana.describe(), e));
+            }
+
+            let status = match dashboard_usage.failures().len() + alert_usage.failures().len() {
+                total if total > failures => Status::Failure,
+                _ => Status::Success,
+            };
+
+            metrics::external::record_grafana_fetch(grafana.name(), status);
+        }
+
+        (dashboard_usage, alert_usage)
+    }
+
+    /// Analyze the dashboards and alert rules of every org of a Grafana instance
+    async fn analyze_grafana_instance(
+        &self,
+        grafana: &Grafana,
+        tenants: &[Tenant],
+        dashboard_usage: &mut TenantUsage,
+        alert_usage: &mut TenantUsage,
+    ) -> anyhow::Result<()> {
+        for grafana in grafana.orgs().await? {
+            // Map datasources to tenants
+            let resolver = grafana
+                .tenant_resolver(
+                    self.config.tenant_mapping.as_ref(),
+                    &self.config.mimir,
+                    tenants,
+                )
+                .await?;
+
+            // mimirtool analyzes the dashboards on its own
+            if self.config.dashboard_analyzer() == DashboardAnalyzer::Native {
+                dashboard_usage.extend(grafana.analyze_dashboards(&resolver).await?);
+            }
+
+            if !self.config.cli.disable_alert_correlation {
+                match grafana.get_alert_rules(alert_usage).await {
+                    Ok(alerts) => alert_usage.extend(grafana.analyze_alerts(&alerts, &resolver)),
+                    Err(e) => {
+                        tracing::error!(
+                            "Failed to fetch alert rules of {}: {}",
+                            grafana.describe(),
+                            e
+                        );
+                        alert_usage.fail(format!("alert rules of {}: {}", grafana.describe(), e));
+                    }
+                }
             }
         }
 
@@ -69,37 +392,203 @@ impl Exporter {
     }
 
     /// Analyze a single tenant
-    #[tracing::instrument(skip(self, alerts))]
-    async fn process_tenant(&self, tenant: &str, alerts: &Vec<Alert>) -> anyhow::Result<()> {
-        let datasources = self.grafana.get_datasources().await?;
-        let used_metrics = self.mimir.analyze_tenant(tenant).await?;
-        let top_metrics = self.mimir.get_tenant_top_metrics(tenant).await?;
-
-        for metric in top_metrics {
-            let in_dashboards = used_metrics.contains(&metric);
-
-            let in_use = match self.config.cli.disable_alert_correlation {
-                true => in_dashboards,
-                false => {
-                    let in_alerts = self
-                        .grafana
-                        .find_metric_in_alerts(tenant, alerts, &datasources, &metric)
-                        .unwrap_or(false);
-
-                    in_dashboards || in_alerts
+    #[tracing::instrument(skip(self, mimir, dashboard_usage, alert_usage), fields(tenant = %tenant))]
+    async fn process_tenant(
+        &self,
+        mimir: &Mimir,
+        tenant: &Tenant,
+        dashboard_usage: &TenantUsage,
+        alert_usage: &TenantUsage,
+    ) -> anyhow::Result<TenantResult> {
+        // Metrics referenced only by sources that failed to load, or by expressions that couldn't
+        // be parsed, can't be told apart from unused ones, so any failure leaves the tenant's
+        // analysis incomplete
+        let mut incomplete_sources: Vec<String> = dashboard_usage
+            .failures_for(tenant)
+            .chain(alert_usage.failures_for(tenant))
+            .cloned()
+            .collect();
+
+        // mimirtool reports the dashboard metrics in use by each tenant separately
+        let tenant_metrics = match self.config.dashboard_analyzer() {
+            DashboardAnalyzer::Native => None,
+            DashboardAnalyzer::Mimirtool => match mimir.analyze_tenant(&tenant.id).await {
+                Ok(metrics) => Some(metrics),
+                Err(e) => {
+                    tracing::error!(
+                        "Failed to analyze tenant '{}' with mimirtool: {}",
+                        tenant,
+                        e
+                    );
+                    incomplete_sources.push(format!("mimirtool: {}", e));
+                    None
+                }
+            },
+        };
+
+        let rule_usage = match mimir.analyze_rules(&tenant.id).await {
+            Ok(rule_usage) => rule_usage,
+            Err(e) => {
+                tracing::error!("Failed to fetch ruler rules of tenant '{}': {}", tenant, e);
+                incomplete_sources.push(format!("ruler rules: {}", e));
+                RuleUsage::default()
+            }
+        };
+
+        incomplete_sources.extend(rule_usage.unparsed.iter().cloned());
+
+        let consumers: Vec<(&Source, &MetricReferences)> = dashboard_usage
+            .for_tenant(tenant)
+            .chain(alert_usage.for_tenant(tenant))
+            .chain(tenant_metrics.as_ref().map(|m| (&Source::Mimirtool, m)))
+            .chain(rule_usage.alerting.iter().map(|(source, m)| (source, m)))
+            .collect();
+
+        let graph = UsageGraph::new(consumers, &rule_usage.recording);
+        let coverage = mimir.coverage();
+        let mut result = TenantResult::new();
+        result.incomplete_sources = incomplete_sources;
+
+        match coverage.mode {
+            CoverageMode::Top => {
+                let limit = coverage.limit.unwrap_or(Coverage::DEFAULT_TOP_LIMIT);
+                let metrics = mimir.get_tenant_top_metrics(&tenant.id, limit).await?;
+
+                self.classify_metrics(tenant, &metrics, &graph, &mut result);
+            }
+            CoverageMode::Full => {
+                let names = mimir
+                    .get_tenant_metric_names(&tenant.id, coverage.limit)
+                    .await?;
+
+                tracing::info!("Analyzing {} metrics in tenant '{}'", names.len(), tenant);
+
+                // Look up cardinality in batches to bound the size of each response
+                for batch in names.chunks(coverage.batch_size.max(1)) {
+                    let metrics = mimir.get_metrics_cardinality(&tenant.id, batch).await?;
+
+                    self.classify_metrics(tenant, &metrics, &graph, &mut result);
                 }
+            }
+        }
+
+        if self.config.recommendations.enabled {
+            self.analyze_labels(mimir, tenant, &graph, &mut result)
+                .await;
+        }
+
+        Ok(result)
+    }
+
+    /// Compare the labels of used metrics with the labels their queries use, recommending
+    /// aggregations for metrics with unused labels.
+    ///
+    /// The labels of up to `LABEL_REQUESTS_CONCURRENCY` metrics are fetched at a time.
+    async fn analyze_labels(
+        &self,
+        mimir: &Mimir,
+        tenant: &Tenant,
+        graph: &UsageGraph<'_>,
+        result: &mut TenantResult,
+    ) {
+        let min_series = self.config.recommendations.min_series;
+
+        let candidates: Vec<(String, LabelUsage)> = result
+            .metrics
+            .iter()
+            .filter(|(_, m)| m.usage.is_some() && m.series_count >= min_series)
+            .filter_map(|(metric, _)| match graph.labels(metric) {
+                // Queries using every label leave nothing to aggregate
+                Some(LabelUsage::All) | None => None,
+                Some(usage) => Some((metric.clone(), usage)),
+            })
+            .collect();
+
+        let mut requests = stream::iter(candidates)
+            .map(|(metric, usage)| async move {
+                let labels = mimir.get_metric_label_names(&tenant.id, &metric).await;
+                (metric, usage, labels)
+            })
+            .buffer_unordered(LABEL_REQUESTS_CONCURRENCY);
+
+        while let Some((metric, usage, labels)) = requests.next().await {
+            let labels = match labels {
+                Ok(labels) => labels,
+                Err(e) => {
+                    tracing::warn!("Failed to fetch labels of metric '{}': {}", metric, e);
+                    continue;
+                }
+            };
+
+            let Some(metric_result) = result.metrics.get_mut(&metric) else {
+                continue;
             };
 
-            metrics::analysis::set_metric(&metric, tenant, in_use);
+            metric_result.labels = labels
+                .into_iter()
+                .map(|label| LabelResult {
+                    used: usage.uses(&label.label_name),
+                    name: label.label_name,
+                    values_count: label.label_values_count,
+                })
+                .collect();
 
-            let status = match in_use {
-                true => "in use",
-                false => "not in use",
+            metric_result.recommendation =
+                aggregation::recommend(metric_result.series_count, &metric_result.labels);
+
+            if let Some(recommendation) = &metric_result.recommendation {
+                tracing::info!(
+                    "Metric '{}' in tenant '{}' can be aggregated to {:?}, removing at least {} series",
+                    metric,
+                    tenant,
+                    recommendation.keep,
+                    recommendation.estimated_reduction
+                );
+            }
+        }
+    }
+
+    /// Classify a set of metrics in a tenant as in use or not in use, adding them to the result
+    fn classify_metrics(
+        &self,
+        tenant: &Tenant,
+        metrics: &[Cardinality],
+        graph: &UsageGraph,
+        result: &mut TenantResult,
+    ) {
+        for Cardinality {
+            label_value: metric,
+            series_count,
+        } in metrics
+        {
+            let usage = graph.usage(metric);
+            let usage_unknown = usage.is_none() && !result.is_complete();
+
+            let status = match &usage {
+                Some(Usage::Direct) => "in use".to_string(),
+                Some(Usage::Via { record, .. }) => format!("in use via '{}'", record),
+                None if usage_unknown => "not in use by the sources that loaded".to_string(),
+                None => "not in use".to_string(),
             };
 
             tracing::info!("Metric '{}' in tenant '{}' is {}", metric, tenant, status);
-        }
 
-        Ok(())
+            result.metrics.insert(
+                metric.clone(),
+                MetricResult {
+                    series_count: *series_count,
+                    usage,
+                    sources: graph.sources(metric),
+                    labels: Vec::new(),
+                    recommendation: None,
+                    first_seen: None,
+                    unused_since: None,
+                    in_grace_period: false,
+                    usage_unknown,
+                    growth: SeriesGrowth::default(),
+                    exploding: false,
+                },
+            );
+        }
     }
 }
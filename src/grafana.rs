@@ -1,37 +1,186 @@
 use crate::{
-    config::Grafana as GrafanaConfig,
-    grafana::{alert::Alert, datasource::Datasource},
-    metrics::{self, external::Target},
+    config::{BasicAuth, Grafana as GrafanaConfig, Mimir as MimirConfig, TenantMapping},
+    grafana::{
+        alert::{Alert, RulerResponse},
+        dashboard::{Dashboard, DashboardResponse, SearchResult},
+        datasource::{Datasource, DatasourceDetails, ScopeOrgId},
+        org::Org,
+        tenant::{Resolution, TenantResolver, tenants_in_name},
+    },
+    metrics::{self, analysis::ExpressionSource, external::Target},
+    mimir::tenant::Tenant,
+    promql::MetricReferences,
+    ratelimit::RateLimiters,
+    usage::{Source, TenantUsage},
 };
-
 pub mod alert;
+pub mod dashboard;
 pub mod datasource;
+pub mod org;
+pub mod tenant;
+
+/// Number of dashboards or folders requested per page from the search API
+const SEARCH_PAGE_SIZE: usize = 1000;
 
+#[derive(Clone)]
 pub struct Grafana {
     config: GrafanaConfig,
     client: reqwest::Client,
+    limits: RateLimiters,
+    credentials: Credentials,
+    /// The org requests are made in, or the token's own org if unset
+    org: Option<Org>,
+}
+
+/// How requests to Grafana are authenticated
+#[derive(Clone)]
+enum Credentials {
+    Token(String),
+    Basic(BasicAuth),
 }
 
 impl Grafana {
     /// Create a new Grafana instance
-    pub fn new(config: GrafanaConfig) -> anyhow::Result<Self> {
-        let client = reqwest::Client::builder()
-            .danger_accept_invalid_certs(config.insecure)
-            .build()?;
+    pub fn new(config: GrafanaConfig, limits: RateLimiters) -> anyhow::Result<Self> {
+        let mut client = reqwest::Client::builder().danger_accept_invalid_certs(config.insecure);
+
+        if let Some(ca_file) = &config.ca_file {
+            let pem = std::fs::read(ca_file)?;
+            client = client.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+
+        let client = client.build()?;
+
+        let credentials = match &config.basic_auth {
+            Some(basic_auth) => Credentials::Basic(basic_auth.clone()),
+            None => Credentials::Token(config.token.clone()),
+        };
+
+        Ok(Self {
+            credentials,
+            config,
+            client,
+            limits,
+            org: None,
+        })
+    }
+
+    /// Build a GET request to the Grafana API, in the selected org
+    fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        let request = self.client.get(format!("{}{}", self.config.url, path));
+
+        let request = match &self.credentials {
+            Credentials::Token(token) => request.bearer_auth(token),
+            Credentials::Basic(auth) => request.basic_auth(&auth.username, Some(&auth.password)),
+        };
+
+        match &self.org {
+            Some(org) => request.header("X-Grafana-Org-Id", org.id),
+            None => request,
+        }
+    }
+
+    /// Name of the Grafana instance
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// Describe the instance and selected org, for error messages
+    pub fn describe(&self) -> String {
+        match &self.org {
+            Some(org) => format!("Grafana instance '{}', org '{}'", self.name(), org.name),
+            None => format!("Grafana instance '{}'", self.name()),
+        }
+    }
+
+    /// Name of the selected org
+    fn org_name(&self) -> String {
+        self.org
+            .as_ref()
+            .map(|o| o.name.clone())
+            .unwrap_or_default()
+    }
+
+    /// Get a client for each org to analyze: the configured orgs, every org if discovery is
+    /// enabled, or else the token's own org
+    #[tracing::instrument(skip(self))]
+    pub async fn orgs(&self) -> anyhow::Result<Vec<Grafana>> {
+        if self.config.discover_orgs {
+            let orgs: Vec<Org> = self.fetch("/api/orgs", "orgs").await?;
+            tracing::info!("Discovered {} Grafana orgs", orgs.len());
+
+            return Ok(orgs.into_iter().map(|org| self.in_org(org, None)).collect());
+        }
+
+        if self.config.orgs.is_empty() {
+            let org: Org = self.fetch("/api/org", "current org").await?;
+
+            return Ok(vec![self.in_org(org, None)]);
+        }
+
+        let mut clients = Vec::new();
+
+        for org in &self.config.orgs {
+            // Look the org up from inside it, which works with org-scoped tokens
+            let placeholder = Org {
+                id: org.id,
+                name: org.id.to_string(),
+            };
+            let client = self.in_org(placeholder, org.token.clone());
+            let org: Org = client.fetch("/api/org", "current org").await?;
+
+            clients.push(client.in_org(org, None));
+        }
+
+        Ok(clients)
+    }
+
+    /// A client making requests in an org, with the org's own token if set
+    fn in_org(&self, org: Org, token: Option<String>) -> Grafana {
+        Grafana {
+            credentials: token
+                .map(Credentials::Token)
+                .unwrap_or_else(|| self.credentials.clone()),
+            org: Some(org),
+            ..self.clone()
+        }
+    }
+
+    /// Fetch and decode a JSON resource
+    async fn fetch<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+        what: &str,
+    ) -> anyhow::Result<T> {
+        self.limits.acquire(Target::Grafana).await;
+        let timer = metrics::external::external_request_timer(Target::Grafana);
+
+        let response = self.get(path).send().await?;
+
+        drop(timer);
 
-        Ok(Self { config, client })
+        if !response.status().is_success() {
+            metrics::external::record_external_request_failure(Target::Grafana);
+
+            return Err(anyhow::anyhow!(
+                "Failed to fetch {}: HTTP {}",
+                what,
+                response.status()
+            ));
+        }
+
+        Ok(response.json::<T>().await?)
     }
 
     /// Get datasources from Grafana
     #[tracing::instrument(skip(self))]
     pub async fn get_datasources(&self) -> anyhow::Result<Vec<Datasource>> {
         tracing::info!("Fetching datasources from Grafana");
+        self.limits.acquire(Target::Grafana).await;
         let timer = metrics::external::external_request_timer(Target::Grafana);
 
         let response = self
-            .client
-            .get(format!("{}/api/datasources", self.config.url))
-            .bearer_auth(self.config.token.clone())
+            .get("/api/datasources")
             .send()
             .await?
             .json::<Vec<Datasource>>()
@@ -42,99 +191,473 @@ impl Grafana {
         Ok(response)
     }
 
-    /// Get alert rules from Grafana
+    /// Get a single datasource, including its settings
     #[tracing::instrument(skip(self))]
-    pub async fn get_alert_rules(&self) -> anyhow::Result<Vec<Alert>> {
-        tracing::info!("Fetching alert rules from Grafana");
+    pub async fn get_datasource_details(&self, uid: &str) -> anyhow::Result<DatasourceDetails> {
+        self.limits.acquire(Target::Grafana).await;
+        let timer = metrics::external::external_request_timer(Target::Grafana);
+
+        let response = self
+            .get(&format!("/api/datasources/uid/{}", uid))
+            .send()
+            .await?;
+
+        drop(timer);
+
+        if !response.status().is_success() {
+            metrics::external::record_external_request_failure(Target::Grafana);
+
+            return Err(anyhow::anyhow!(
+                "Failed to fetch datasource '{}': HTTP {}",
+                uid,
+                response.status()
+            ));
+        }
+
+        Ok(response.json::<DatasourceDetails>().await?)
+    }
+
+    /// Build a resolver from datasources to the tenants they query.
+    ///
+    /// Without a tenant mapping, a datasource queries every tenant whose ID is a word of its name.
+    /// A datasource queries the cluster set by its mapping rule, or the cluster whose
+    /// `datasources` match its name. Otherwise, its tenants are looked up in every cluster.
+    ///
+    /// Prometheus datasources whose tenants can't be determined are left unresolved, so that
+    /// their queries are credited to every tenant rather than dropped.
+    #[tracing::instrument(skip(self, mapping, clusters, tenants))]
+    pub async fn tenant_resolver(
+        &self,
+        mapping: Option<&TenantMapping>,
+        clusters: &[MimirConfig],
+        tenants: &[Tenant],
+    ) -> anyhow::Result<TenantResolver> {
+        let datasources = self.get_datasources().await?;
+        let mut resolver = TenantResolver::new(datasources.clone());
+
+        if mapping.is_none() {
+            tracing::warn!(
+                "No tenantMapping is configured, attributing datasources of {} to the tenants \
+                 their names mention",
+                self.describe()
+            );
+        }
+
+        for ds in &datasources {
+            let rule = mapping.and_then(|mapping| {
+                mapping
+                    .datasources
+                    .iter()
+                    .find(|r| r.matches(&ds.uid, &ds.name))
+            });
+
+            let ids: Vec<String> = match (mapping, rule) {
+                (_, Some(rule)) => rule.tenants(),
+                (Some(mapping), None) => self.detect_tenants(mapping, ds).await?,
+                (None, None) => tenants_in_name(&ds.name, tenants.iter().map(|t| t.id.as_str())),
+            };
+
+            let cluster = rule.and_then(|r| r.cluster.as_deref()).or_else(|| {
+                clusters
+                    .iter()
+                    .find(|c| c.queried_by(&ds.name))
+                    .map(|c| c.name.as_str())
+            });
+
+            resolver.map(ds, &ids, cluster, tenants);
+
+            match resolver.resolve_uid(&ds.uid) {
+                Resolution::Tenants(resolved) => {
+                    tracing::debug!("Datasource '{}' queries tenants {:?}", ds.name, resolved);
+                }
+                Resolution::Unresolved => tracing::warn!(
+                    "Can't determine the tenants datasource '{}' of {} queries, crediting its \
+                     queries to every tenant. Add a tenant mapping for it.",
+                    ds.name,
+                    self.describe()
+                ),
+                Resolution::Unmapped => {}
+            }
+        }
+
+        Ok(resolver)
+    }
+
+    /// Detect the tenants a datasource queries from its `X-Scope-OrgID` header
+    async fn detect_tenants(
+        &self,
+        mapping: &TenantMapping,
+        ds: &Datasource,
+    ) -> anyhow::Result<Vec<String>> {
+        if !mapping.detect || !ds.is_prometheus() {
+            return Ok(Vec::new());
+        }
+
+        let tenants = match self.get_datasource_details(&ds.uid).await?.scope_org_id() {
+            ScopeOrgId::Value(value) => value.split('|').map(String::from).collect(),
+            ScopeOrgId::Secure => {
+                tracing::warn!(
+                    "Datasource '{}' of {} sets X-Scope-OrgID as a secure field, which can't be \
+                     read. Add a tenant mapping for it.",
+                    ds.name,
+                    self.describe()
+                );
+                Vec::new()
+            }
+            ScopeOrgId::Missing => Vec::new(),
+        };
+
+        Ok(tenants)
+    }
+
+    /// Search for all dashboards in Grafana
+    pub async fn search_dashboards(&self) -> anyhow::Result<Vec<SearchResult>> {
+        tracing::info!("Searching dashboards in Grafana");
+        self.search("dash-db").await
+    }
+
+    /// Search for all folders in Grafana, including nested ones
+    pub async fn search_folders(&self) -> anyhow::Result<Vec<SearchResult>> {
+        tracing::info!("Searching folders in Grafana");
+        self.search("dash-folder").await
+    }
+
+    /// Search for all items of a type, paging through the results
+    #[tracing::instrument(skip(self))]
+    async fn search(&self, kind: &str) -> anyhow::Result<Vec<SearchResult>> {
+        let mut items = Vec::new();
+
+        for page in 1.. {
+            self.limits.acquire(Target::Grafana).await;
+            let timer = metrics::external::external_request_timer(Target::Grafana);
+
+            let response = self
+                .get("/api/search")
+                .query(&[
+                    ("type", kind),
+                    ("limit", &SEARCH_PAGE_SIZE.to_string()),
+                    ("page", &page.to_string()),
+                ])
+                .send()
+                .await?;
+
+            drop(timer);
+
+            if !response.status().is_success() {
+                metrics::external::record_external_request_failure(Target::Grafana);
+
+                return Err(anyhow::anyhow!(
+                    "Failed to search '{}': HTTP {}",
+                    kind,
+                    response.status()
+                ));
+            }
+
+            let results = response.json::<Vec<SearchResult>>().await?;
+            let last_page = results.len() < SEARCH_PAGE_SIZE;
+            items.extend(results);
+
+            if last_page {
+                break;
+            }
+        }
+
+        Ok(items)
+    }
+
+    /// Get a dashboard by UID
+    #[tracing::instrument(skip(self))]
+    pub async fn get_dashboard(&self, uid: &str) -> anyhow::Result<Dashboard> {
+        self.limits.acquire(Target::Grafana).await;
         let timer = metrics::external::external_request_timer(Target::Grafana);
 
         let response = self
-            .client
-            .get(format!(
-                "{}/api/v1/provisioning/alert-rules",
-                self.config.url
-            ))
-            .bearer_auth(self.config.token.clone())
+            .get(&format!("/api/dashboards/uid/{}", uid))
             .send()
             .await?;
 
         drop(timer);
 
-        let body = response.text().await?;
-        let alerts: Vec<Alert> = serde_json::from_str(&body)?;
+        if !response.status().is_success() {
+            metrics::external::record_external_request_failure(Target::Grafana);
+
+            return Err(anyhow::anyhow!(
+                "Failed to fetch dashboard '{}': HTTP {}",
+                uid,
+                response.status()
+            ));
+        }
+
+        Ok(response.json::<DashboardResponse>().await?.dashboard)
+    }
+
+    /// Analyze all dashboards and return the metrics they reference per tenant.
+    ///
+    /// Each query is credited to the tenants its datasource queries. Queries against datasources
+    /// that can't be resolved are credited to every tenant. Dashboards that fail to load are
+    /// recorded as failures, since their metrics would otherwise look unused.
+    #[tracing::instrument(skip(self, resolver))]
+    pub async fn analyze_dashboards(
+        &self,
+        resolver: &TenantResolver,
+    ) -> anyhow::Result<TenantUsage> {
+        tracing::info!("Analyzing metric usage in dashboards");
+        let mut usage = TenantUsage::default();
+
+        let dashboards = self.search_dashboards().await?;
+        tracing::info!("Found {} dashboards", dashboards.len());
+
+        for result in dashboards {
+            let dashboard = match self.get_dashboard(&result.uid).await {
+                Ok(dashboard) => dashboard,
+                Err(e) => {
+                    tracing::warn!("{}", e);
+                    usage.fail(format!("{}: {}", self.describe(), e));
+                    continue;
+                }
+            };
+            let variables = dashboard.datasource_variables();
+
+            for query in dashboard.queries() {
+                let tenants = match resolver.resolve(&query.datasource, &variables) {
+                    Resolution::Tenants(tenants) => Some(tenants),
+                    Resolution::Unresolved => None,
+                    Resolution::Unmapped => continue,
+                };
+
+                let metrics = match MetricReferences::from_expr(&query.expr) {
+                    Ok(metrics) => metrics,
+                    Err(e) => {
+                        tracing::debug!(
+                            "Failed to parse expression in dashboard '{}': {}",
+                            dashboard.title,
+                            e
+                        );
+                        metrics::analysis::record_parse_failure(ExpressionSource::Dashboard);
+                        usage.unparsed(
+                            tenants.as_deref(),
+                            format!(
+                                "unparsable query in dashboard '{}' of {}: {}",
+                                dashboard.title,
+                                self.describe(),
+                                e
+                            ),
+                        );
+                        continue;
+                    }
+                };
+
+                let source = Source::Dashboard {
+                    grafana: self.name().to_string(),
+                    org: self.org_name(),
+                    uid: dashboard.uid.clone(),
+                    title: dashboard.title.clone(),
+                    panel: query.panel,
+                };
+
+                match tenants {
+                    Some(tenants) => {
+                        for tenant in tenants {
+                            usage.add_tenant(&tenant, source.clone(), metrics.clone());
+                        }
+                    }
+                    None => usage.add_all(source, metrics),
+                }
+            }
+        }
+
+        Ok(usage)
+    }
 
-        let alerts = alerts
-            .into_iter()
-            .filter_map(|mut alert| {
-                // Keep only AlertData that have a model with a set expr
-                alert.data.retain(|d| d.model.expr.is_some());
+    /// Get the Grafana-managed alert rules of every folder.
+    ///
+    /// Folders whose rules fail to load are skipped and recorded as failures in the usage, since
+    /// their metrics would otherwise look unused.
+    #[tracing::instrument(skip(self, usage))]
+    pub async fn get_alert_rules(&self, usage: &mut TenantUsage) -> anyhow::Result<Vec<Alert>> {
+        tracing::info!("Fetching alert rules from Grafana");
+        let mut alerts = Vec::new();
 
-                // Drop the entire alert if it has no data left
-                if alert.data.is_empty() {
-                    None
-                } else {
-                    Some(alert)
+        // Rules are fetched a folder at a time to keep responses small
+        for folder in self.search_folders().await? {
+            match self.get_folder_alert_rules(&folder).await {
+                Ok(folder_alerts) => alerts.extend(folder_alerts),
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to fetch alert rules of folder '{}': {}",
+                        folder.title,
+                        e
+                    );
+                    usage.fail(format!(
+                        "alert rules of folder '{}' of {}: {}",
+                        folder.title,
+                        self.describe(),
+                        e
+                    ));
                 }
+            }
+        }
+
+        tracing::info!("Found {} alert rules", alerts.len());
+
+        Ok(alerts)
+    }
+
+    /// Get the Grafana-managed alert rules of a folder
+    async fn get_folder_alert_rules(&self, folder: &SearchResult) -> anyhow::Result<Vec<Alert>> {
+        self.limits.acquire(Target::Grafana).await;
+        let timer = metrics::external::external_request_timer(Target::Grafana);
+
+        let response = self
+            .get(&format!("/api/ruler/grafana/api/v1/rules/{}", folder.uid))
+            .send()
+            .await?;
+
+        drop(timer);
+
+        // Folders without rules
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+
+        if !response.status().is_success() {
+            metrics::external::record_external_request_failure(Target::Grafana);
+
+            return Err(anyhow::anyhow!("HTTP {}", response.status()));
+        }
+
+        let alerts = response
+            .json::<RulerResponse>()
+            .await?
+            .into_values()
+            .flatten()
+            .flat_map(|group| {
+                group
+                    .rules
+                    .into_iter()
+                    .filter_map(|rule| rule.grafana_alert)
+                    .map(move |alert| Alert::new(alert, &folder.title, &group.name))
             })
             .collect();
 
         Ok(alerts)
     }
 
-    /// Iterate over alert rules in Grafana and find a metric by name in alerts that use tenant datasources
-    #[tracing::instrument(skip(self, alerts, datasources))]
-    pub fn find_metric_in_alerts(
-        &self,
-        tenant: &str,
-        alerts: &Vec<Alert>,
-        datasources: &[Datasource],
-        metric_name: &str,
-    ) -> anyhow::Result<bool> {
-        let metric_regex = regex::Regex::new(&format!(r"\b{}\b", regex::escape(metric_name)))?;
+    /// Get the metrics referenced by alert rules.
+    ///
+    /// Each datasource query of the alert is credited to the tenants its datasource queries,
+    /// whether or not the alert's condition depends on it. Queries against other datasources,
+    /// such as Loki, are left out, and queries against unknown datasources are credited to every
+    /// tenant.
+    #[tracing::instrument(skip(self, alerts, resolver))]
+    pub fn analyze_alerts(&self, alerts: &[Alert], resolver: &TenantResolver) -> TenantUsage {
+        let mut usage = TenantUsage::default();
 
         for alert in alerts {
-            if self.alert_contains_metric(alert, &metric_regex, datasources, tenant) {
-                tracing::info!(
-                    "Metric '{}' found in alert '{}' for tenant '{}'",
-                    metric_name,
-                    alert.title,
-                    tenant
-                );
+            let source = Source::Alert {
+                grafana: self.name().to_string(),
+                org: self.org_name(),
+                uid: alert.uid.clone(),
+                title: alert.title.clone(),
+                folder: alert.folder.clone(),
+                group: alert.group.clone(),
+            };
+
+            for query in alert.queries() {
+                let (Some(uid), Some(expr)) = (&query.datasource_uid, query.expr()) else {
+                    continue;
+                };
+
+                let tenants = match resolver.resolve_uid(uid) {
+                    Resolution::Tenants(tenants) => Some(tenants),
+                    Resolution::Unresolved => None,
+                    Resolution::Unmapped => continue,
+                };
+
+                let metrics = match MetricReferences::from_expr(expr) {
+                    Ok(metrics) => metrics,
+                    Err(e) => {
+                        tracing::warn!(
+                            "Failed to parse query '{}' in alert '{}': {}",
+                            query.ref_id,
+                            alert.title,
+                            e
+                        );
+                        metrics::analysis::record_parse_failure(ExpressionSource::Alert);
+                        usage.unparsed(
+                            tenants.as_deref(),
+                            format!(
+                                "unparsable query in alert '{}' of {}: {}",
+                                alert.title,
+                                self.describe(),
+                                e
+                            ),
+                        );
+                        continue;
+                    }
+                };
 
-                return Ok(true);
+                match tenants {
+                    Some(tenants) => {
+                        for tenant in tenants {
+                            usage.add_tenant(&tenant, source.clone(), metrics.clone());
+                        }
+                    }
+                    None => usage.add_all(source.clone(), metrics),
+                }
             }
         }
 
-        Ok(false)
+        usage
     }
+}
 
-    /// Check if an alert contains a metric matching the given regex and uses a tenant datasource
-    #[tracing::instrument(skip_all)]
-    fn alert_contains_metric(
-        &self,
-        alert: &Alert,
-        metric_regex: &regex::Regex,
-        datasources: &[Datasource],
-        tenant: &str,
-    ) -> bool {
-        let has_metric = alert.data.iter().any(|alert_data| {
-            alert_data
-                .model
-                .expr
-                .as_ref()
-                .map(|expr| metric_regex.is_match(expr))
-                .unwrap_or(false)
-        });
-
-        let uses_tenant_datasource = alert.data.iter().any(|alert_data| {
-            alert_data
-                .datasource_uid
-                .as_ref()
-                .and_then(|uid| datasources.iter().find(|ds| &ds.uid == uid))
-                .map(|ds| ds.name.contains(tenant))
-                .unwrap_or(false)
-        });
-
-        has_metric && uses_tenant_datasource
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{DashboardAnalyzer, RateLimits},
+        grafana::alert::GrafanaAlert,
+    };
+
+    #[test]
+    fn alerts_on_unmapped_prometheus_datasources_count_for_every_tenant() {
+        let config = GrafanaConfig::new(
+            "http://grafana".to_string(),
+            Some("token".to_string()),
+            None,
+            false,
+            DashboardAnalyzer::Native,
+        )
+        .unwrap();
+        let grafana =
+            Grafana::new(config, RateLimiters::new(&RateLimits::default()).unwrap()).unwrap();
+
+        let datasources: Vec<Datasource> = serde_json::from_str(
+            r#"[{"id": 1, "uid": "mimir", "name": "Mimir", "type": "prometheus"}]"#,
+        )
+        .unwrap();
+        let tenants = [Tenant::new("main", "prod"), Tenant::new("main", "dev")];
+        let mut resolver = TenantResolver::new(datasources.clone());
+        resolver.map(&datasources[0], &[], None, &tenants);
+
+        let alert: GrafanaAlert = serde_json::from_str(
+            r#"{
+                "uid": "a",
+                "title": "High latency",
+                "condition": "A",
+                "data": [{"refId": "A", "datasourceUid": "mimir", "model": {"expr": "up"}}]
+            }"#,
+        )
+        .unwrap();
+        let usage = grafana.analyze_alerts(&[Alert::new(alert, "folder", "group")], &resolver);
+
+        for tenant in &tenants {
+            let referenced: Vec<_> = usage.for_tenant(tenant).collect();
+
+            assert_eq!(referenced.len(), 1);
+            assert!(referenced[0].1.contains("up"));
+            assert_eq!(usage.failures_for(tenant).count(), 0);
+        }
     }
 }
@@ -1,15 +1,49 @@
 use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Datasource UIDs of server-side expressions, the second one used by older Grafana versions
+const EXPRESSION_DATASOURCES: &[&str] = &["__expr__", "-100"];
+
+/// Rule groups of a folder, keyed by the folder's title, as returned by the ruler API
+pub type RulerResponse = BTreeMap<String, Vec<RuleGroup>>;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RuleGroup {
+    pub name: String,
+    #[serde(default)]
+    pub rules: Vec<RulerRule>,
+}
 
 #[derive(Deserialize, Debug, Clone)]
+pub struct RulerRule {
+    /// Unset for rules not managed by Grafana
+    #[serde(default)]
+    pub grafana_alert: Option<GrafanaAlert>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GrafanaAlert {
+    pub uid: String,
+    pub title: String,
+    #[serde(default)]
+    pub data: Vec<AlertQuery>,
+}
+
+/// A Grafana-managed alert rule, with the folder and rule group it belongs to
+#[derive(Debug, Clone)]
 pub struct Alert {
-    pub id: usize,
     pub uid: String,
     pub title: String,
-    pub data: Vec<AlertData>,
+    pub folder: String,
+    pub group: String,
+    pub data: Vec<AlertQuery>,
 }
 
+/// A query or server-side expression of an alert rule
 #[derive(Deserialize, Debug, Clone)]
-pub struct AlertData {
+pub struct AlertQuery {
+    #[serde(rename = "refId", default)]
+    pub ref_id: String,
     #[serde(rename = "datasourceUid")]
     pub datasource_uid: Option<String>,
     pub model: AlertDataModel,
@@ -19,4 +53,45 @@ pub struct AlertData {
 pub struct AlertDataModel {
     #[serde(default)]
     pub expr: Option<String>,
+    /// Used instead of `expr` by some datasources
+    #[serde(default)]
+    pub query: Option<String>,
+}
+
+impl Alert {
+    pub fn new(alert: GrafanaAlert, folder: &str, group: &str) -> Self {
+        Self {
+            uid: alert.uid,
+            title: alert.title,
+            folder: folder.to_string(),
+            group: group.to_string(),
+            data: alert.data,
+        }
+    }
+
+    /// The datasource queries of the alert.
+    ///
+    /// Grafana evaluates every query, including those the condition doesn't depend on, since
+    /// annotations and labels can read them through `$values`.
+    pub fn queries(&self) -> Vec<&AlertQuery> {
+        self.data.iter().filter(|q| !q.is_expression()).collect()
+    }
+}
+
+impl AlertQuery {
+    /// Check whether this is a server-side expression rather than a datasource query
+    pub fn is_expression(&self) -> bool {
+        self.datasource_uid
+            .as_deref()
+            .is_some_and(|uid| EXPRESSION_DATASOURCES.contains(&uid))
+    }
+
+    /// The query text sent to the datasource
+    pub fn expr(&self) -> Option<&str> {
+        self.model
+            .expr
+            .as_deref()
+            .or(self.model.query.as_deref())
+            .filter(|expr| !expr.trim().is_empty())
+    }
 }
@@ -0,0 +1,239 @@
+use crate::grafana::datasource::DatasourceRef;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SearchResult {
+    pub uid: String,
+    pub title: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DashboardResponse {
+    pub dashboard: Dashboard,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Dashboard {
+    pub uid: String,
+    pub title: String,
+    #[serde(default)]
+    pub panels: Vec<Panel>,
+    /// Rows from the legacy (pre-5.0) dashboard schema
+    #[serde(default)]
+    pub rows: Vec<Row>,
+    #[serde(default)]
+    pub templating: Templating,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Row {
+    #[serde(default)]
+    pub panels: Vec<Panel>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Panel {
+    #[serde(default)]
+    pub id: Option<i64>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub datasource: Option<DatasourceRef>,
+    #[serde(default)]
+    pub targets: Vec<Target>,
+    /// Panels nested in a collapsed row
+    #[serde(default)]
+    pub panels: Vec<Panel>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Target {
+    #[serde(default)]
+    pub expr: Option<String>,
+    /// The target's own datasource, overriding the panel datasource
+    #[serde(default)]
+    pub datasource: Option<DatasourceRef>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Templating {
+    #[serde(default)]
+    pub list: Vec<Variable>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Variable {
+    #[serde(default)]
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub kind: String,
+    #[serde(default)]
+    pub query: Option<serde_json::Value>,
+    /// For datasource variables, a regex limiting the selectable datasources by name
+    #[serde(default)]
+    pub regex: Option<String>,
+    /// For query variables, the datasource the query runs against
+    #[serde(default)]
+    pub datasource: Option<DatasourceRef>,
+}
+
+/// A query expression in a dashboard, along with the datasource it runs against
+#[derive(Debug, Clone)]
+pub struct Query {
+    pub expr: String,
+    pub datasource: DatasourceRef,
+    /// The panel the query belongs to, or `None` for template variables
+    pub panel: Option<String>,
+}
+
+/// The datasources a datasource template variable can select
+#[derive(Debug, Clone)]
+pub struct DatasourceVariable {
+    /// The datasource plugin type, such as `prometheus`
+    pub kind: String,
+    pub regex: Option<Regex>,
+}
+
+impl Dashboard {
+    /// Iterate over all panels in the dashboard, including those nested in rows
+    pub fn all_panels(&self) -> Vec<&Panel> {
+        let mut panels = Vec::new();
+        let mut stack: Vec<&Panel> = self
+            .panels
+            .iter()
+            .chain(self.rows.iter().flat_map(|row| row.panels.iter()))
+            .collect();
+
+        while let Some(panel) = stack.pop() {
+            stack.extend(panel.panels.iter());
+            panels.push(panel);
+        }
+
+        panels
+    }
+
+    /// Get all query expressions in the dashboard, including those of query variables
+    pub fn queries(&self) -> Vec<Query> {
+        let targets = self.all_panels().into_iter().flat_map(|panel| {
+            panel.targets.iter().filter_map(|target| {
+                Some(Query {
+                    expr: target.expr.clone()?,
+                    datasource: panel.target_datasource(target),
+                    panel: Some(panel.name()),
+                })
+            })
+        });
+
+        let variables = self.templating.list.iter().filter_map(|variable| {
+            Some(Query {
+                expr: variable.expression()?,
+                datasource: variable.datasource.clone().unwrap_or_default(),
+                panel: None,
+            })
+        });
+
+        targets.chain(variables).collect()
+    }
+
+    /// Get the datasource template variables, by name
+    pub fn datasource_variables(&self) -> HashMap<String, DatasourceVariable> {
+        self.templating
+            .list
+            .iter()
+            .filter(|variable| variable.kind == "datasource")
+            .filter_map(|variable| {
+                let kind = variable.query.as_ref()?.as_str()?.to_string();
+
+                // Invalid regexes match nothing in Grafana either
+                let regex = match variable.regex.as_deref().filter(|r| !r.is_empty()) {
+                    Some(regex) => Some(parse_grafana_regex(regex)?),
+                    None => None,
+                };
+
+                Some((variable.name.clone(), DatasourceVariable { kind, regex }))
+            })
+            .collect()
+    }
+}
+
+impl Panel {
+    /// Get a name identifying the panel: its title, or its ID if untitled
+    pub fn name(&self) -> String {
+        match (&self.title, self.id) {
+            (Some(title), _) if !title.is_empty() => title.clone(),
+            (_, Some(id)) => format!("Panel {}", id),
+            _ => "Untitled panel".to_string(),
+        }
+    }
+
+    /// Get the datasource a target of this panel runs against.
+    ///
+    /// Targets use the panel datasource, unless the panel mixes datasources or doesn't set one.
+    pub fn target_datasource(&self, target: &Target) -> DatasourceRef {
+        match &self.datasource {
+            Some(datasource) if !datasource.is_mixed() => datasource.clone(),
+            _ => target
+                .datasource
+                .clone()
+                .or_else(|| self.datasource.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl Variable {
+    /// Get the PromQL expression behind a query variable, if any.
+    ///
+    /// Handles the `label_values(<selector>, <label>)` and `query_result(<expr>)` forms.
+    pub fn expression(&self) -> Option<String> {
+        if self.kind != "query" {
+            return None;
+        }
+
+        // The query is either a plain string or an object with a `query` field
+        let query = match self.query.as_ref()? {
+            serde_json::Value::String(query) => query.as_str(),
+            serde_json::Value::Object(query) => query.get("query")?.as_str()?,
+            _ => return None,
+        }
+        .trim();
+
+        if let Some(inner) = strip_call(query, "query_result") {
+            return Some(inner.to_string());
+        }
+
+        if let Some(inner) = strip_call(query, "label_values") {
+            // The selector is optional; with a single argument, only a label is given
+            let (selector, _label) = inner.rsplit_once(',')?;
+
+            return Some(selector.trim().to_string());
+        }
+
+        None
+    }
+}
+
+/// Parse a regex as written in Grafana, either plain or in `/pattern/flags` form
+fn parse_grafana_regex(regex: &str) -> Option<Regex> {
+    let pattern = match regex
+        .strip_prefix('/')
+        .and_then(|rest| rest.rsplit_once('/'))
+    {
+        Some((pattern, flags)) if flags.contains('i') => format!("(?i){}", pattern),
+        Some((pattern, _)) => pattern.to_string(),
+        None => regex.to_string(),
+    };
+
+    Regex::new(&pattern).ok()
+}
+
+/// Strip a `name(...)` call, returning its arguments
+fn strip_call<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .strip_prefix(name)?
+        .trim_start()
+        .strip_prefix('(')?
+        .strip_suffix(')')
+}
@@ -1,8 +1,138 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Datasource {
     pub id: usize,
     pub uid: String,
     pub name: String,
+    #[serde(rename = "type", default)]
+    pub kind: String,
+    #[serde(rename = "isDefault", default)]
+    pub is_default: bool,
+}
+
+/// A datasource as returned by `/api/datasources/uid/{uid}`
+#[derive(Deserialize, Debug, Clone)]
+pub struct DatasourceDetails {
+    #[serde(rename = "jsonData", default)]
+    pub json_data: HashMap<String, serde_json::Value>,
+    #[serde(rename = "secureJsonFields", default)]
+    pub secure_json_fields: HashMap<String, bool>,
+}
+
+/// A reference to a datasource from a dashboard panel or target
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub enum DatasourceRef {
+    Uid(String),
+    /// A datasource referenced by name, as in dashboards predating datasource UIDs
+    Name(String),
+    /// No datasource set, meaning the default datasource
+    #[default]
+    Default,
+}
+
+impl Datasource {
+    /// Check whether this is a Prometheus datasource, which may query Mimir
+    pub fn is_prometheus(&self) -> bool {
+        self.kind == "prometheus"
+    }
+}
+
+impl DatasourceRef {
+    /// Check whether this references the special datasource for panels mixing datasources
+    pub fn is_mixed(&self) -> bool {
+        match self {
+            DatasourceRef::Uid(value) | DatasourceRef::Name(value) => value == "-- Mixed --",
+            DatasourceRef::Default => false,
+        }
+    }
+
+    /// Get the name of the template variable this references, if any
+    pub fn variable(&self) -> Option<&str> {
+        let value = match self {
+            DatasourceRef::Uid(value) | DatasourceRef::Name(value) => value,
+            DatasourceRef::Default => return None,
+        };
+
+        if let Some(inner) = value.strip_prefix("${") {
+            // `${name:format}`
+            return inner.strip_suffix('}')?.split(':').next();
+        }
+
+        if let Some(inner) = value.strip_prefix("[[") {
+            return inner.strip_suffix("]]");
+        }
+
+        value.strip_prefix('$')
+    }
+}
+
+impl DatasourceDetails {
+    /// Get the `X-Scope-OrgID` header value configured on the datasource.
+    ///
+    /// Grafana stores custom header values as secure fields, which are never returned by the API,
+    /// so this only finds values kept in `jsonData`.
+    pub fn scope_org_id(&self) -> ScopeOrgId {
+        let index = self.json_data.iter().find_map(|(key, value)| {
+            let index = key.strip_prefix("httpHeaderName")?;
+
+            value
+                .as_str()
+                .filter(|name| name.eq_ignore_ascii_case("X-Scope-OrgID"))
+                .map(|_| index.to_string())
+        });
+
+        let Some(index) = index else {
+            return ScopeOrgId::Missing;
+        };
+
+        let value_key = format!("httpHeaderValue{}", index);
+
+        if let Some(value) = self.json_data.get(&value_key).and_then(|v| v.as_str()) {
+            return ScopeOrgId::Value(value.to_string());
+        }
+
+        if self
+            .secure_json_fields
+            .get(&value_key)
+            .copied()
+            .unwrap_or(false)
+        {
+            return ScopeOrgId::Secure;
+        }
+
+        ScopeOrgId::Missing
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ScopeOrgId {
+    Missing,
+    /// The header is configured, but its value is stored as a secure field
+    Secure,
+    Value(String),
+}
+
+impl<'de> Deserialize<'de> for DatasourceRef {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Name(String),
+            Object { uid: Option<String> },
+        }
+
+        let reference = match Option::<Raw>::deserialize(deserializer)? {
+            None => DatasourceRef::Default,
+            Some(Raw::Name(name)) => DatasourceRef::Name(name),
+            Some(Raw::Object { uid: Some(uid) }) => DatasourceRef::Uid(uid),
+            Some(Raw::Object { uid: None }) => DatasourceRef::Default,
+        };
+
+        Ok(reference)
+    }
 }
@@ -0,0 +1,8 @@
+use serde::Deserialize;
+
+/// A Grafana organization, as returned by `/api/org` and `/api/orgs`
+#[derive(Deserialize, Debug, Clone)]
+pub struct Org {
+    pub id: u64,
+    pub name: String,
+}
@@ -0,0 +1,238 @@
+use crate::{
+    grafana::{
+        dashboard::DatasourceVariable,
+        datasource::{Datasource, DatasourceRef},
+    },
+    mimir::tenant::Tenant,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    ops::Range,
+};
+
+/// Resolves Grafana datasources to the Mimir tenants they query
+#[derive(Debug, Clone, Default)]
+pub struct TenantResolver {
+    datasources: Vec<Datasource>,
+    /// Tenants per datasource UID. Datasources whose tenants can't be determined are left out.
+    tenants: HashMap<String, Vec<Tenant>>,
+}
+
+/// The outcome of resolving a datasource reference
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// The datasource queries these tenants
+    Tenants(Vec<Tenant>),
+    /// The datasource is known not to query any analyzed tenant
+    Unmapped,
+    /// The reference can't be resolved to a datasource, or the datasource's tenants can't be
+    /// determined
+    Unresolved,
+}
+
+impl TenantResolver {
+    /// Create a resolver for a list of datasources, none of them mapped to tenants yet
+    pub fn new(datasources: Vec<Datasource>) -> Self {
+        Self {
+            datasources,
+            tenants: HashMap::new(),
+        }
+    }
+
+    /// Map a datasource to the tenants with the given IDs, in a cluster if set.
+    ///
+    /// A Prometheus datasource without any ID may still query Mimir, so it is left unresolved.
+    /// Datasources of other types are known not to query any tenant.
+    pub fn map(
+        &mut self,
+        datasource: &Datasource,
+        ids: &[String],
+        cluster: Option<&str>,
+        tenants: &[Tenant],
+    ) {
+        if ids.is_empty() && datasource.is_prometheus() {
+            return;
+        }
+
+        let resolved = tenants
+            .iter()
+            .filter(|tenant| ids.contains(&tenant.id))
+            .filter(|tenant| cluster.is_none_or(|c| tenant.cluster == c))
+            .cloned()
+            .collect();
+
+        self.tenants.insert(datasource.uid.clone(), resolved);
+    }
+
+    /// Resolve a datasource reference to the tenants it queries.
+    ///
+    /// References to datasource template variables resolve to every datasource the variable can
+    /// select, using the variables defined in the referencing dashboard.
+    pub fn resolve(
+        &self,
+        reference: &DatasourceRef,
+        variables: &HashMap<String, DatasourceVariable>,
+    ) -> Resolution {
+        if let Some(name) = reference.variable() {
+            return match variables.get(name) {
+                Some(variable) => self.resolve_variable(variable),
+                None => Resolution::Unresolved,
+            };
+        }
+
+        let datasource = match reference {
+            DatasourceRef::Uid(uid) => self.datasources.iter().find(|ds| &ds.uid == uid),
+            DatasourceRef::Name(name) => self.datasources.iter().find(|ds| &ds.name == name),
+            DatasourceRef::Default => self.datasources.iter().find(|ds| ds.is_default),
+        };
+
+        match datasource {
+            Some(datasource) => self.resolve_datasource(datasource),
+            None => Resolution::Unresolved,
+        }
+    }
+
+    /// Resolve a datasource UID to the tenants it queries
+    pub fn resolve_uid(&self, uid: &str) -> Resolution {
+        self.resolve(&DatasourceRef::Uid(uid.to_string()), &HashMap::new())
+    }
+
+    /// Resolve a datasource variable to the tenants of all datasources it can select. If any of
+    /// them can't be resolved, neither can the variable.
+    fn resolve_variable(&self, variable: &DatasourceVariable) -> Resolution {
+        let mut tenants = BTreeSet::new();
+
+        let datasources = self
+            .datasources
+            .iter()
+            .filter(|ds| ds.kind == variable.kind)
+            .filter(|ds| {
+                variable
+                    .regex
+                    .as_ref()
+                    .is_none_or(|re| re.is_match(&ds.name))
+            });
+
+        for datasource in datasources {
+            match self.resolve_datasource(datasource) {
+                Resolution::Tenants(found) => tenants.extend(found),
+                Resolution::Unmapped => {}
+                Resolution::Unresolved => return Resolution::Unresolved,
+            }
+        }
+
+        match tenants.is_empty() {
+            true => Resolution::Unmapped,
+            false => Resolution::Tenants(tenants.into_iter().collect()),
+        }
+    }
+
+    fn resolve_datasource(&self, datasource: &Datasource) -> Resolution {
+        match self.tenants.get(&datasource.uid) {
+            Some(tenants) if !tenants.is_empty() => Resolution::Tenants(tenants.clone()),
+            Some(_) => Resolution::Unmapped,
+            None => Resolution::Unresolved,
+        }
+    }
+}
+
+/// Find the tenant IDs a datasource name mentions as whole words, such as `prod` in
+/// `Mimir (prod)` but not in `Mimir production`.
+///
+/// An ID only mentioned as part of a longer mentioned ID, such as `prod` in `Mimir prod-eu` when
+/// `prod-eu` is a tenant too, doesn't count.
+pub fn tenants_in_name<'a>(name: &str, ids: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mentions: Vec<(&str, Range<usize>)> = ids
+        .into_iter()
+        .filter(|id| !id.is_empty())
+        .flat_map(|id| {
+            name.match_indices(id)
+                .map(|(start, _)| start..start + id.len())
+                .filter(|range| is_word(name, range))
+                .map(move |range| (id, range))
+        })
+        .collect();
+
+    let within_longer = |id: &str, range: &Range<usize>| {
+        mentions.iter().any(|(other, other_range)| {
+            other.len() > id.len()
+                && other_range.start <= range.start
+                && range.end <= other_range.end
+        })
+    };
+
+    let ids: BTreeSet<&str> = mentions
+        .iter()
+        .filter(|(id, range)| !within_longer(id, range))
+        .map(|(id, _)| *id)
+        .collect();
+
+    ids.into_iter().map(String::from).collect()
+}
+
+/// Check whether a part of a name is delimited by non-alphanumeric characters
+fn is_word(name: &str, range: &Range<usize>) -> bool {
+    let before = name[..range.start].chars().next_back();
+    let after = name[range.end..].chars().next();
+
+    !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datasource(uid: &str, kind: &str) -> Datasource {
+        Datasource {
+            id: 1,
+            uid: uid.to_string(),
+            name: uid.to_string(),
+            kind: kind.to_string(),
+            is_default: false,
+        }
+    }
+
+    #[test]
+    fn unmapped_prometheus_datasources_are_unresolved() {
+        let tenants = [Tenant::new("main", "prod"), Tenant::new("main", "dev")];
+        let datasources = vec![
+            datasource("mapped", "prometheus"),
+            datasource("unmapped", "prometheus"),
+            datasource("logs", "loki"),
+        ];
+        let mut resolver = TenantResolver::new(datasources.clone());
+
+        resolver.map(&datasources[0], &["prod".to_string()], None, &tenants);
+        resolver.map(&datasources[1], &[], None, &tenants);
+        resolver.map(&datasources[2], &[], None, &tenants);
+
+        assert_eq!(
+            resolver.resolve_uid("mapped"),
+            Resolution::Tenants(vec![tenants[0].clone()])
+        );
+        assert_eq!(resolver.resolve_uid("unmapped"), Resolution::Unresolved);
+        assert_eq!(resolver.resolve_uid("logs"), Resolution::Unmapped);
+        assert_eq!(resolver.resolve_uid("missing"), Resolution::Unresolved);
+    }
+
+    #[test]
+    fn tenants_are_matched_as_words() {
+        let ids = ["prod", "prod-eu", "dev"];
+
+        assert_eq!(tenants_in_name("Mimir (prod)", ids), ["prod"]);
+        assert_eq!(tenants_in_name("mimir-prod", ids), ["prod"]);
+        assert_eq!(tenants_in_name("Mimir prod-eu", ids), ["prod-eu"]);
+        assert_eq!(
+            tenants_in_name("Mimir production", ids),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            tenants_in_name("Mimir prod, prod-eu", ids),
+            ["prod", "prod-eu"]
+        );
+        assert_eq!(
+            tenants_in_name("Mimir dev/prod-eu", ids),
+            ["dev", "prod-eu"]
+        );
+    }
+}
@@ -0,0 +1,379 @@
+use crate::{
+    config::{Growth, History},
+    mimir::tenant::Tenant,
+    results::{MetricResult, SeriesGrowth, TenantResult},
+};
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+use std::{path::Path, sync::Mutex};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS tenants (
+    cluster TEXT NOT NULL,
+    tenant TEXT NOT NULL,
+    first_cycle INTEGER NOT NULL,
+    PRIMARY KEY (cluster, tenant)
+);
+
+CREATE TABLE IF NOT EXISTS metrics (
+    cluster TEXT NOT NULL,
+    tenant TEXT NOT NULL,
+    metric TEXT NOT NULL,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    unused_since INTEGER,
+    PRIMARY KEY (cluster, tenant, metric)
+);
+
+CREATE TABLE IF NOT EXISTS cycles (
+    cluster TEXT NOT NULL,
+    tenant TEXT NOT NULL,
+    metric TEXT NOT NULL,
+    analyzed_at INTEGER NOT NULL,
+    used INTEGER NOT NULL,
+    series_count INTEGER NOT NULL,
+    PRIMARY KEY (cluster, tenant, metric, analyzed_at)
+);
+";
+
+/// Local store of each metric's status per cycle, kept in an SQLite database.
+///
+/// Timestamps are stored as Unix timestamps in seconds.
+pub struct HistoryStore {
+    connection: Mutex<Connection>,
+    grace_period: Duration,
+    retention: Duration,
+    exploding_ratio: Option<f64>,
+    exploding_min_series: usize,
+}
+
+/// What the history knows about a metric before the current cycle
+struct MetricHistory {
+    first_seen: i64,
+    unused_since: Option<i64>,
+}
+
+impl HistoryStore {
+    /// Open the history database, creating it if needed
+    pub fn open(path: &Path, history: &History, growth: &Growth) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+            grace_period: Duration::seconds(history.grace_period as i64),
+            retention: Duration::seconds(history.retention as i64),
+            exploding_ratio: growth.exploding_ratio,
+            exploding_min_series: growth.min_series,
+        })
+    }
+
+    /// Record a tenant's result, filling in when each metric was first seen, since when it has
+    /// been unused and how much its series count grew.
+    ///
+    /// Metrics seen in a tenant's first recorded cycle predate the history, so they are never
+    /// considered new. Metrics first seen later are within the grace period until it has passed.
+    pub fn record(&self, tenant: &Tenant, result: &mut TenantResult) -> anyhow::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction()?;
+        let now = result.analyzed_at.timestamp();
+
+        let (cluster, tenant_id) = (&tenant.cluster, &tenant.id);
+
+        tx.execute(
+            "INSERT OR IGNORE INTO tenants (cluster, tenant, first_cycle) VALUES (?1, ?2, ?3)",
+            params![cluster, tenant_id, now],
+        )?;
+
+        let first_cycle: i64 = tx.query_row(
+            "SELECT first_cycle FROM tenants WHERE cluster = ?1 AND tenant = ?2",
+            params![cluster, tenant_id],
+            |row| row.get(0),
+        )?;
+
+        {
+            let mut select = tx.prepare(
+                "SELECT first_seen, unused_since FROM metrics
+                 WHERE cluster = ?1 AND tenant = ?2 AND metric = ?3",
+            )?;
+            let mut upsert = tx.prepare(
+                "INSERT INTO metrics (cluster, tenant, metric, first_seen, last_seen, unused_since)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT (cluster, tenant, metric) DO UPDATE
+                 SET last_seen = excluded.last_seen, unused_since = excluded.unused_since",
+            )?;
+            let mut past_series = tx.prepare(
+                "SELECT series_count FROM cycles
+                 WHERE cluster = ?1 AND tenant = ?2 AND metric = ?3 AND analyzed_at <= ?4
+                 ORDER BY analyzed_at DESC LIMIT 1",
+            )?;
+            let mut insert_cycle = tx.prepare(
+                "INSERT OR REPLACE INTO cycles
+                 (cluster, tenant, metric, analyzed_at, used, series_count)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+
+            for (metric, metric_result) in result.metrics.iter_mut() {
+                let history = select
+                    .query_row(params![cluster, tenant_id, metric], |row| {
+                        Ok(MetricHistory {
+                            first_seen: row.get(0)?,
+                            unused_since: row.get(1)?,
+                        })
+                    })
+                    .optional()?
+                    .unwrap_or(MetricHistory {
+                        first_seen: now,
+                        unused_since: None,
+                    });
+
+                // Whether a metric of an incomplete analysis is unused isn't known, so it keeps
+                // its previous state
+                let used = metric_result.usage.is_some();
+                let unused_since = match (used, metric_result.usage_unknown) {
+                    (true, _) => None,
+                    (false, true) => history.unused_since,
+                    (false, false) => Some(history.unused_since.unwrap_or(now)),
+                };
+
+                // Compare to the latest cycle at least a day, or a week, before this one
+                let mut ratio = |window: Duration| -> rusqlite::Result<Option<f64>> {
+                    let past: Option<i64> = past_series
+                        .query_row(
+                            params![cluster, tenant_id, metric, now - window.num_seconds()],
+                            |row| row.get(0),
+                        )
+                        .optional()?;
+
+                    Ok(past
+                        .filter(|&past| past > 0)
+                        .map(|past| metric_result.series_count as f64 / past as f64))
+                };
+
+                metric_result.growth = SeriesGrowth {
+                    day: ratio(Duration::days(1))?,
+                    week: ratio(Duration::days(7))?,
+                };
+
+                upsert.execute(params![
+                    cluster,
+                    tenant_id,
+                    metric,
+                    history.first_seen,
+                    now,
+                    unused_since
+                ])?;
+                insert_cycle.execute(params![
+                    cluster,
+                    tenant_id,
+                    metric,
+                    now,
+                    used,
+                    metric_result.series_count as i64
+                ])?;
+
+                self.apply(metric_result, &history, unused_since, first_cycle, now);
+
+                if metric_result.exploding {
+                    tracing::warn!(
+                        "Metric '{}' in tenant '{}' is exploding, growth: {:?}",
+                        metric,
+                        tenant,
+                        metric_result.growth
+                    );
+                }
+            }
+        }
+
+        // Forget cycles, and metrics that haven't been seen, past the retention period
+        let cutoff = now - self.retention.num_seconds();
+
+        tx.execute(
+            "DELETE FROM cycles WHERE cluster = ?1 AND tenant = ?2 AND analyzed_at < ?3",
+            params![cluster, tenant_id, cutoff],
+        )?;
+        tx.execute(
+            "DELETE FROM metrics WHERE cluster = ?1 AND tenant = ?2 AND last_seen < ?3",
+            params![cluster, tenant_id, cutoff],
+        )?;
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Fill in a metric's result from its history
+    fn apply(
+        &self,
+        result: &mut MetricResult,
+        history: &MetricHistory,
+        unused_since: Option<i64>,
+        first_cycle: i64,
+        now: i64,
+    ) {
+        result.first_seen = timestamp(history.first_seen);
+        result.unused_since = unused_since.and_then(timestamp);
+        result.in_grace_period = result.usage.is_none()
+            && history.first_seen > first_cycle
+            && now - history.first_seen < self.grace_period.num_seconds();
+
+        // A metric that appeared after the first cycle and has no cycle a day old to compare to
+        // grew from nothing
+        let appeared = history.first_seen > first_cycle && result.growth.day.is_none();
+
+        result.exploding = self.exploding_ratio.is_some_and(|threshold| {
+            result.series_count >= self.exploding_min_series
+                && (appeared || result.growth.windows().any(|(_, ratio)| ratio >= threshold))
+        });
+    }
+}
+
+fn timestamp(seconds: i64) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp(seconds, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage::Usage;
+    use std::collections::BTreeMap;
+
+    fn store(history: &str, growth: &str) -> HistoryStore {
+        let history: History = serde_norway::from_str(history).unwrap();
+        let growth: Growth = serde_norway::from_str(growth).unwrap();
+
+        HistoryStore::open(Path::new(":memory:"), &history, &growth).unwrap()
+    }
+
+    fn metric(series_count: usize, used: bool) -> MetricResult {
+        MetricResult {
+            series_count,
+            usage: used.then_some(Usage::Direct),
+            sources: Vec::new(),
+            labels: Vec::new(),
+            recommendation: None,
+            first_seen: None,
+            unused_since: None,
+            in_grace_period: false,
+            usage_unknown: false,
+            growth: SeriesGrowth::default(),
+            exploding: false,
+        }
+    }
+
+    /// Record a cycle `hours` after the first one, returning the recorded metrics
+    fn record(
+        store: &HistoryStore,
+        hours: i64,
+        metrics: &[(&str, MetricResult)],
+    ) -> BTreeMap<String, MetricResult> {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut result = TenantResult {
+            analyzed_at: start + Duration::hours(hours),
+            metrics: metrics
+                .iter()
+                .map(|(name, m)| (name.to_string(), m.clone()))
+                .collect(),
+            incomplete_sources: Vec::new(),
+        };
+
+        store
+            .record(&Tenant::new("main", "prod"), &mut result)
+            .unwrap();
+
+        result.metrics
+    }
+
+    #[test]
+    fn new_metrics_are_in_the_grace_period() {
+        let store = store("gracePeriod: 86400", "{}");
+
+        let first = record(&store, 0, &[("old", metric(10, false))]);
+        assert!(!first["old"].in_grace_period);
+        assert_eq!(first["old"].unused_since, first["old"].first_seen);
+
+        let second = record(
+            &store,
+            1,
+            &[("old", metric(10, false)), ("new", metric(10, false))],
+        );
+        assert!(!second["old"].in_grace_period);
+        assert!(second["new"].in_grace_period);
+        assert_eq!(second["old"].first_seen, first["old"].first_seen);
+
+        let third = record(
+            &store,
+            25,
+            &[("old", metric(10, false)), ("new", metric(10, false))],
+        );
+        assert!(!third["new"].in_grace_period);
+        assert_eq!(third["new"].unused_since, second["new"].first_seen);
+    }
+
+    #[test]
+    fn unused_since_follows_usage() {
+        let store = store("{}", "{}");
+
+        let first = record(&store, 0, &[("a", metric(10, false))]);
+        let unused_since = first["a"].unused_since;
+        assert!(unused_since.is_some());
+
+        // An incomplete analysis keeps the previous state
+        let mut unknown = metric(10, false);
+        unknown.usage_unknown = true;
+        assert_eq!(
+            record(&store, 1, &[("a", unknown.clone())])["a"].unused_since,
+            unused_since
+        );
+
+        assert_eq!(
+            record(&store, 2, &[("a", metric(10, true))])["a"].unused_since,
+            None
+        );
+        assert_eq!(record(&store, 3, &[("a", unknown)])["a"].unused_since, None);
+
+        let later = record(&store, 4, &[("a", metric(10, false))]);
+        assert!(later["a"].unused_since > unused_since);
+    }
+
+    #[test]
+    fn growth_is_compared_to_a_day_and_a_week_before() {
+        let store = store("{}", "{explodingRatio: 2.0, minSeries: 100}");
+
+        let first = record(&store, 0, &[("a", metric(100, true))]);
+        assert_eq!(first["a"].growth, SeriesGrowth::default());
+        assert!(!first["a"].exploding);
+
+        record(&store, 1, &[("a", metric(150, true))]);
+
+        // Compared to the latest cycle at least a day before
+        let next_day = record(&store, 26, &[("a", metric(300, true))]);
+        assert_eq!(next_day["a"].growth.day, Some(2.0));
+        assert_eq!(next_day["a"].growth.week, None);
+        assert!(next_day["a"].exploding);
+
+        let next_week = record(&store, 24 * 7, &[("a", metric(300, true))]);
+        assert_eq!(next_week["a"].growth.day, Some(1.0));
+        assert_eq!(next_week["a"].growth.week, Some(3.0));
+    }
+
+    #[test]
+    fn metrics_appearing_with_many_series_are_exploding() {
+        let store = store("{}", "{explodingRatio: 2.0, minSeries: 100}");
+
+        record(&store, 0, &[("a", metric(10, true))]);
+
+        let appeared = record(
+            &store,
+            1,
+            &[
+                ("a", metric(10, true)),
+                ("big", metric(500, true)),
+                ("small", metric(50, true)),
+            ],
+        );
+        assert!(appeared["big"].exploding);
+        assert!(!appeared["small"].exploding);
+        assert!(!appeared["a"].exploding);
+    }
+}
@@ -1,13 +1,19 @@
-use crate::{config::Config, metrics::METRICS_HANDLE};
-use axum::{Router, response::IntoResponse, routing::get};
+use crate::{config::Config, metrics::METRICS_HANDLE, results::RESULTS};
+use axum::{
+    Router,
+    response::IntoResponse,
+    routing::{get, post},
+};
 use hyper::StatusCode;
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
+
+mod api;
 
 /// Creates an Axum Web Server
 pub async fn create_server(config: Config) {
     tracing::info!("Starting the web server");
 
-    let app = create_router();
+    let app = create_router(Arc::new(config.clone()));
 
     let addr: SocketAddr = format!("{}:{}", config.http.host, config.http.port)
         .parse()
@@ -22,10 +28,22 @@ pub async fn create_server(config: Config) {
 }
 
 /// Create the router for the application
-fn create_router() -> Router {
+fn create_router(config: Arc<Config>) -> Router {
     Router::new()
         .route("/alive", get(alive))
         .route("/metrics", get(metrics))
+        .route("/api/v1/tenants", get(api::tenants))
+        .route("/api/v1/tenants/{id}/metrics", get(api::tenant_metrics))
+        .route(
+            "/api/v1/tenants/{id}/unused-labels",
+            get(api::unused_labels),
+        )
+        .route("/api/v1/metrics/{name}", get(api::metric))
+        .route("/api/v1/recommendations", get(api::recommendations))
+        .route("/api/v1/drop-rules", get(api::drop_rules))
+        .route("/api/v1/analyze", post(api::analyze))
+        .route("/api/v1/jobs/{id}", get(api::job))
+        .with_state(config)
 }
 
 /// This is the handler for the /alive path
@@ -43,7 +61,12 @@ async fn metrics() -> impl IntoResponse {
     let _timer = crate::metrics::http::http_request_timer("/metrics");
 
     match METRICS_HANDLE.get().unwrap() {
-        Some(handle) => (StatusCode::OK, handle.render()),
+        Some(handle) => {
+            let mut body = handle.render();
+            body.push_str(&crate::metrics::analysis::render_results(&RESULTS));
+
+            (StatusCode::OK, body)
+        }
         None => {
             crate::metrics::http::record_http_request("/metrics");
 
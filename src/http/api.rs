@@ -0,0 +1,537 @@
+use crate::{
+    aggregation::{LabelResult, Recommendation},
+    config::Config,
+    drop_rules::{self, Format},
+    jobs::{JOBS, JobId, Scope},
+    mimir::tenant::Tenant,
+    results::{MetricResult, MetricStatus, RESULTS, SeriesGrowth, TenantResult},
+    usage::{Source, Usage},
+};
+use axum::{
+    Json,
+    extract::{
+        Path, Query, State,
+        rejection::{PathRejection, QueryRejection},
+    },
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Summary of a tenant's latest analysis
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TenantSummary {
+    pub cluster: String,
+    pub tenant: String,
+    pub analyzed_at: DateTime<Utc>,
+    pub metrics: usize,
+    pub unused_metrics: usize,
+    pub unused_series: usize,
+    /// Whether every usage source loaded. Metrics that look unused in an incomplete analysis are
+    /// reported as unknown.
+    pub complete: bool,
+    /// Usage sources that failed to load
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub incomplete_sources: Vec<String>,
+}
+
+/// Analysis result of a metric in a tenant
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricSummary {
+    pub metric: String,
+    pub cluster: String,
+    pub tenant: String,
+    pub status: MetricStatus,
+    pub series_count: usize,
+    /// The recording rule the metric is used through, if not used directly
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub used_via: Option<String>,
+    /// Number of recording rules between the metric and its consumer, if used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depth: Option<usize>,
+    /// Dashboards, alerts and rules referencing the metric
+    pub sources: Vec<Source>,
+    /// The metric's labels, if they were analyzed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<LabelResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recommendation: Option<Recommendation>,
+    /// When the analyzer first saw the metric, if history is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_seen: Option<DateTime<Utc>>,
+    /// When the metric became unused, if it is unused and history is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unused_since: Option<DateTime<Utc>>,
+    /// Growth of the series count, if history is enabled
+    pub growth: SeriesGrowth,
+    pub exploding: bool,
+    pub analyzed_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricSort {
+    /// By metric name
+    #[default]
+    Name,
+    /// By series count, highest first
+    Series,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MetricsQuery {
+    /// The cluster of the tenant, required if several clusters have a tenant with its ID
+    pub cluster: Option<String>,
+    pub status: Option<MetricStatus>,
+    #[serde(default)]
+    pub sort: MetricSort,
+}
+
+/// A label of a used metric that no query depends on
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UnusedLabel {
+    pub metric: String,
+    pub label: String,
+    pub values_count: usize,
+    pub series_count: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TenantQuery {
+    /// The cluster of the tenant, required if several clusters have a tenant with its ID
+    pub cluster: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RecommendationsQuery {
+    /// Only list recommendations for tenants of this cluster
+    pub cluster: Option<String>,
+    /// Only list recommendations for this tenant
+    pub tenant: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DropRulesQuery {
+    pub format: Format,
+    /// Only generate drop rules for tenants of this cluster
+    pub cluster: Option<String>,
+    /// Only generate drop rules for this tenant
+    pub tenant: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AnalyzeQuery {
+    /// Only analyze tenants of this cluster
+    pub cluster: Option<String>,
+    /// Only analyze this tenant
+    pub tenant: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl MetricSummary {
+    fn new(
+        metric: &str,
+        tenant: &Tenant,
+        result: &MetricResult,
+        analyzed_at: DateTime<Utc>,
+    ) -> Self {
+        let (used_via, depth) = match &result.usage {
+            Some(Usage::Direct) => (None, Some(0)),
+            Some(Usage::Via { record, depth }) => (Some(record.clone()), Some(*depth)),
+            None => (None, None),
+        };
+
+        Self {
+            metric: metric.to_string(),
+            cluster: tenant.cluster.clone(),
+            tenant: tenant.id.clone(),
+            status: result.status(),
+            series_count: result.series_count,
+            used_via,
+            depth,
+            sources: result.sources.clone(),
+            labels: result.labels.clone(),
+            recommendation: result.recommendation.clone(),
+            first_seen: result.first_seen,
+            unused_since: result.unused_since,
+            growth: result.growth,
+            exploding: result.exploding,
+            analyzed_at,
+        }
+    }
+}
+
+/// Respond with a JSON error message
+fn error(status: StatusCode, message: String) -> Response {
+    (status, Json(ErrorResponse { error: message })).into_response()
+}
+
+/// Respond to invalid query parameters with a JSON error message
+fn query_error(rejection: QueryRejection) -> Response {
+    error(rejection.status(), rejection.body_text())
+}
+
+/// Respond to invalid path parameters with a JSON error message
+fn path_error(rejection: PathRejection) -> Response {
+    error(rejection.status(), rejection.body_text())
+}
+
+/// Find the latest result of a tenant in a cluster, or in the only cluster with a tenant with its
+/// ID if unset. Fails with the status and message to respond with.
+fn find_tenant(
+    id: &str,
+    cluster: Option<String>,
+) -> Result<(Tenant, TenantResult), (StatusCode, String)> {
+    let not_found = || {
+        (
+            StatusCode::NOT_FOUND,
+            format!("Tenant '{}' has not been analyzed", id),
+        )
+    };
+
+    let cluster = match cluster {
+        Some(cluster) => cluster,
+        None => {
+            let clusters = RESULTS.clusters_of(id);
+
+            match clusters.as_slice() {
+                [] => return Err(not_found()),
+                [cluster] => cluster.clone(),
+                _ => {
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        format!(
+                            "Tenant '{}' exists in clusters {}, select one with cluster=<name>",
+                            id,
+                            clusters.join(", ")
+                        ),
+                    ));
+                }
+            }
+        }
+    };
+
+    let tenant = Tenant::new(cluster, id);
+
+    match RESULTS.get(&tenant) {
+        Some(result) => Ok((tenant, result)),
+        None => Err(not_found()),
+    }
+}
+
+/// This is the handler for the /api/v1/tenants path
+pub async fn tenants() -> Json<Vec<TenantSummary>> {
+    crate::metrics::http::record_http_request("/api/v1/tenants");
+    let _timer = crate::metrics::http::http_request_timer("/api/v1/tenants");
+
+    let mut tenants = Vec::new();
+
+    RESULTS.visit(|view| {
+        tenants.push(TenantSummary {
+            cluster: view.tenant.cluster.clone(),
+            tenant: view.tenant.id.clone(),
+            analyzed_at: view.result.analyzed_at,
+            metrics: view.result.metrics.len(),
+            unused_metrics: view
+                .result
+                .metrics
+                .values()
+                .filter(|m| m.status() == MetricStatus::Unused)
+                .count(),
+            unused_series: view.result.unused_series(),
+            complete: view.result.is_complete(),
+            incomplete_sources: view.result.incomplete_sources.clone(),
+        });
+    });
+
+    Json(tenants)
+}
+
+/// This is the handler for the /api/v1/tenants/{id}/metrics path
+pub async fn tenant_metrics(
+    Path(tenant): Path<String>,
+    query: Result<Query<MetricsQuery>, QueryRejection>,
+) -> Response {
+    crate::metrics::http::record_http_request("/api/v1/tenants/{id}/metrics");
+    let _timer = crate::metrics::http::http_request_timer("/api/v1/tenants/{id}/metrics");
+
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(rejection) => return query_error(rejection),
+    };
+
+    let (tenant, result) = match find_tenant(&tenant, query.cluster) {
+        Ok(found) => found,
+        Err((status, message)) => return error(status, message),
+    };
+
+    let mut metrics: Vec<MetricSummary> = result
+        .metrics
+        .iter()
+        .map(|(metric, m)| MetricSummary::new(metric, &tenant, m, result.analyzed_at))
+        .filter(|m| query.status.is_none_or(|status| m.status == status))
+        .collect();
+
+    // Metrics are already sorted by name
+    if let MetricSort::Series = query.sort {
+        metrics.sort_by_key(|m| std::cmp::Reverse(m.series_count));
+    }
+
+    Json(metrics).into_response()
+}
+
+/// This is the handler for the /api/v1/metrics/{name} path
+pub async fn metric(Path(name): Path<String>) -> Response {
+    crate::metrics::http::record_http_request("/api/v1/metrics/{name}");
+    let _timer = crate::metrics::http::http_request_timer("/api/v1/metrics/{name}");
+
+    let mut tenants = Vec::new();
+
+    RESULTS.visit(|view| {
+        if let Some(result) = view.result.metrics.get(&name) {
+            tenants.push(MetricSummary::new(
+                &name,
+                view.tenant,
+                result,
+                view.result.analyzed_at,
+            ));
+        }
+    });
+
+    if tenants.is_empty() {
+        return error(
+            StatusCode::NOT_FOUND,
+            format!("Metric '{}' was not found in any tenant", name),
+        );
+    }
+
+    Json(tenants).into_response()
+}
+
+/// This is the handler for the /api/v1/tenants/{id}/unused-labels path
+pub async fn unused_labels(
+    tenant: Result<Path<String>, PathRejection>,
+    query: Result<Query<TenantQuery>, QueryRejection>,
+) -> Response {
+    crate::metrics::http::record_http_request("/api/v1/tenants/{id}/unused-labels");
+    let _timer = crate::metrics::http::http_request_timer("/api/v1/tenants/{id}/unused-labels");
+
+    let Path(tenant) = match tenant {
+        Ok(tenant) => tenant,
+        Err(rejection) => return path_error(rejection),
+    };
+
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(rejection) => return query_error(rejection),
+    };
+
+    let (_, result) = match find_tenant(&tenant, query.cluster) {
+        Ok(found) => found,
+        Err((status, message)) => return error(status, message),
+    };
+
+    let mut labels: Vec<UnusedLabel> = result
+        .metrics
+        .iter()
+        .flat_map(|(metric, m)| {
+            m.labels
+                .iter()
+                .filter(|label| !label.used)
+                .map(|label| UnusedLabel {
+                    metric: metric.clone(),
+                    label: label.name.clone(),
+                    values_count: label.values_count,
+                    series_count: m.series_count,
+                })
+        })
+        .collect();
+
+    // Highest cardinality labels first
+    labels.sort_by_key(|label| std::cmp::Reverse(label.values_count));
+
+    Json(labels).into_response()
+}
+
+/// This is the handler for the /api/v1/recommendations path
+pub async fn recommendations(
+    query: Result<Query<RecommendationsQuery>, QueryRejection>,
+) -> Response {
+    crate::metrics::http::record_http_request("/api/v1/recommendations");
+    let _timer = crate::metrics::http::http_request_timer("/api/v1/recommendations");
+
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(rejection) => return query_error(rejection),
+    };
+
+    let mut metrics = Vec::new();
+
+    RESULTS.visit(|view| {
+        let scope = Scope {
+            cluster: query.cluster.clone(),
+            tenant: query.tenant.clone(),
+        };
+
+        if !scope.includes(view.tenant) {
+            return;
+        }
+
+        let recommended = view
+            .result
+            .metrics
+            .iter()
+            .filter(|(_, result)| result.recommendation.is_some())
+            .map(|(metric, result)| {
+                MetricSummary::new(metric, view.tenant, result, view.result.analyzed_at)
+            });
+
+        metrics.extend(recommended);
+    });
+
+    // Largest reductions first
+    metrics.sort_by_key(|m| {
+        std::cmp::Reverse(
+            m.recommendation
+                .as_ref()
+                .map_or(0, |r| r.estimated_reduction),
+        )
+    });
+
+    Json(metrics).into_response()
+}
+
+/// This is the handler for the /api/v1/drop-rules path
+pub async fn drop_rules(
+    State(config): State<Arc<Config>>,
+    query: Result<Query<DropRulesQuery>, QueryRejection>,
+) -> Response {
+    crate::metrics::http::record_http_request("/api/v1/drop-rules");
+    let _timer = crate::metrics::http::http_request_timer("/api/v1/drop-rules");
+
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(rejection) => return query_error(rejection),
+    };
+
+    let mut unused = drop_rules::unused_metrics(&RESULTS, &config.drop_rules);
+
+    let scope = Scope {
+        cluster: query.cluster,
+        tenant: query.tenant,
+    };
+
+    unused.retain(|tenant, _| scope.includes(tenant));
+
+    if query.format == Format::Mimir && drop_rules::clusters(&unused).len() > 1 {
+        return error(
+            StatusCode::BAD_REQUEST,
+            "Mimir drop rules are generated per cluster, select one with cluster=<name>"
+                .to_string(),
+        );
+    }
+
+    match drop_rules::render(query.format, &unused) {
+        Ok(rules) => rules.into_response(),
+        Err(e) => error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to render drop rules: {}", e),
+        ),
+    }
+}
+
+/// This is the handler for the /api/v1/analyze path
+pub async fn analyze(
+    State(config): State<Arc<Config>>,
+    query: Result<Query<AnalyzeQuery>, QueryRejection>,
+) -> Response {
+    crate::metrics::http::record_http_request("/api/v1/analyze");
+    let _timer = crate::metrics::http::http_request_timer("/api/v1/analyze");
+
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(rejection) => return query_error(rejection),
+    };
+
+    if let Some(cluster) = &query.cluster
+        && !config.mimir.iter().any(|mimir| mimir.name == *cluster)
+    {
+        return error(
+            StatusCode::NOT_FOUND,
+            format!("Cluster '{}' is not configured", cluster),
+        );
+    }
+
+    let scope = Scope {
+        cluster: query.cluster,
+        tenant: query.tenant,
+    };
+
+    if !JOBS.knows(&scope) {
+        return error(StatusCode::NOT_FOUND, format!("{} was not found", scope));
+    }
+
+    let job = JOBS.submit(scope);
+
+    (StatusCode::ACCEPTED, Json(job)).into_response()
+}
+
+/// This is the handler for the /api/v1/jobs/{id} path
+pub async fn job(id: Result<Path<JobId>, PathRejection>) -> Response {
+    crate::metrics::http::record_http_request("/api/v1/jobs/{id}");
+    let _timer = crate::metrics::http::http_request_timer("/api/v1/jobs/{id}");
+
+    let Path(id) = match id {
+        Ok(id) => id,
+        Err(rejection) => return path_error(rejection),
+    };
+
+    match JOBS.get(id) {
+        Some(job) => Json(job).into_response(),
+        None => error(StatusCode::NOT_FOUND, format!("Job {} was not found", id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, body::Body, routing::get};
+    use http::Request;
+    use tower::ServiceExt;
+
+    /// Send a GET request to a router and return the response status and JSON body
+    async fn get_json(router: Router, uri: &str) -> (StatusCode, serde_json::Value) {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn invalid_parameters_get_json_errors() {
+        let router = Router::new()
+            .route("/api/v1/jobs/{id}", get(job))
+            .route("/api/v1/recommendations", get(recommendations));
+
+        let (status, body) = get_json(router.clone(), "/api/v1/jobs/latest").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].as_str().unwrap().contains("latest"));
+
+        let (status, body) = get_json(router, "/api/v1/recommendations?tenant=a&tenant=b").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"].is_string());
+    }
+}
@@ -0,0 +1,391 @@
+use crate::mimir::tenant::Tenant;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::Mutex};
+use tokio::sync::Notify;
+
+/// Analyses requested over HTTP, picked up by the exporter loop
+pub static JOBS: Lazy<JobQueue> = Lazy::new(JobQueue::default);
+
+/// Number of jobs kept after they finish, so their outcome can still be looked up
+const FINISHED_JOBS_KEPT: usize = 100;
+
+pub type JobId = u64;
+
+/// A requested analysis
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub id: JobId,
+    #[serde(flatten)]
+    pub scope: Scope,
+    pub status: JobStatus,
+    pub queued_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Number of tenants to analyze, known once the job has started
+    pub tenants: usize,
+    /// Number of tenants analyzed successfully
+    pub done: usize,
+    /// Tenants that failed to be analyzed
+    pub failed: Vec<Tenant>,
+    /// Why the whole analysis failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The tenants a job analyzes
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Scope {
+    /// Only analyze tenants of this cluster
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<String>,
+    /// Only analyze tenants with this ID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    /// Finished, though some tenants may have failed
+    Completed,
+    Failed,
+}
+
+/// Requested analyses, coalesced until they finish.
+///
+/// A request joins a queued or running job whose scope covers it, such as a job covering every
+/// tenant. Every queued job is then run by the same analysis cycle.
+#[derive(Default)]
+pub struct JobQueue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    next_id: JobId,
+    jobs: BTreeMap<JobId, Job>,
+    queued: Vec<JobId>,
+    /// The tenants found by the latest discovery of each cluster, unknown until the first cycle
+    tenants: Option<Vec<Tenant>>,
+}
+
+/// The jobs run by an analysis cycle. The default batch has no jobs and covers every tenant.
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    jobs: Vec<JobId>,
+    /// The scopes to analyze, or every tenant if unset
+    scopes: Option<Vec<Scope>>,
+}
+
+impl JobQueue {
+    /// Queue an analysis of the tenants in a scope. Returns the job, which may be a queued or
+    /// running job the request was coalesced with.
+    pub fn submit(&self, scope: Scope) -> Job {
+        let mut state = self.state.lock().unwrap();
+
+        let coalesced = state.jobs.values().find(|job| {
+            matches!(job.status, JobStatus::Queued | JobStatus::Running) && job.scope.covers(&scope)
+        });
+
+        if let Some(job) = coalesced {
+            return job.clone();
+        }
+
+        state.next_id += 1;
+        let id = state.next_id;
+
+        let job = Job {
+            id,
+            scope,
+            status: JobStatus::Queued,
+            queued_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+            tenants: 0,
+            done: 0,
+            failed: Vec::new(),
+            error: None,
+        };
+
+        state.jobs.insert(id, job.clone());
+        state.queued.push(id);
+        self.notify.notify_one();
+
+        job
+    }
+
+    /// Check whether a scope covers any tenant found by the latest discovery. Any scope is
+    /// accepted until the first discovery.
+    pub fn knows(&self, scope: &Scope) -> bool {
+        let state = self.state.lock().unwrap();
+
+        state.tenants.as_ref().is_none_or(|tenants| {
+            scope.is_all() || tenants.iter().any(|tenant| scope.includes(tenant))
+        })
+    }
+
+    /// Get a job
+    pub fn get(&self, id: JobId) -> Option<Job> {
+        self.state.lock().unwrap().jobs.get(&id).cloned()
+    }
+
+    /// Wait until a job is queued
+    pub async fn wait(&self) {
+        self.notify.notified().await
+    }
+
+    /// Start every queued job. The batch covers every tenant if `all` is set, or any of the jobs
+    /// does.
+    pub fn start(&self, all: bool) -> Batch {
+        let mut state = self.state.lock().unwrap();
+        let now = Utc::now();
+        let ids = std::mem::take(&mut state.queued);
+        let mut scopes = (!all).then(Vec::new);
+
+        for id in &ids {
+            let job = state.jobs.get_mut(id).expect("queued jobs are kept");
+            job.status = JobStatus::Running;
+            job.started_at = Some(now);
+
+            match (job.scope.is_all(), &mut scopes) {
+                (true, _) => scopes = None,
+                (false, Some(scopes)) => scopes.push(job.scope.clone()),
+                (false, None) => {}
+            }
+        }
+
+        Batch { jobs: ids, scopes }
+    }
+
+    /// Set the number of tenants each job of a batch covers, once the tenants of some clusters are
+    /// discovered. Jobs for a tenant or cluster that wasn't discovered fail.
+    pub fn discovered(&self, batch: &Batch, clusters: &[&str], tenants: &[Tenant]) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let known = state.tenants.get_or_insert_default();
+
+            known.retain(|tenant| !clusters.contains(&tenant.cluster.as_str()));
+            known.extend_from_slice(tenants);
+        }
+
+        self.update(batch, |job| {
+            job.tenants = tenants.iter().filter(|t| job.scope.includes(t)).count();
+
+            if job.tenants == 0 && !job.scope.is_all() {
+                job.status = JobStatus::Failed;
+                job.finished_at = Some(Utc::now());
+                job.error = Some(format!("{} was not found", job.scope));
+            }
+        });
+    }
+
+    /// Record the outcome of a tenant's analysis in the jobs covering it
+    pub fn tenant_finished(&self, batch: &Batch, tenant: &Tenant, success: bool) {
+        self.update(batch, |job| {
+            if !job.scope.includes(tenant) {
+                return;
+            }
+
+            match success {
+                true => job.done += 1,
+                false => job.failed.push(tenant.clone()),
+            }
+        });
+    }
+
+    /// Finish the jobs of a batch, with the error that failed the whole analysis if any
+    pub fn finish(&self, batch: &Batch, error: Option<&anyhow::Error>) {
+        self.update(batch, |job| {
+            if job.status != JobStatus::Running {
+                return;
+            }
+
+            job.finished_at = Some(Utc::now());
+
+            match error {
+                Some(e) => {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(e.to_string());
+                }
+                None => job.status = JobStatus::Completed,
+            }
+        });
+
+        // Forget the oldest finished jobs
+        let mut state = self.state.lock().unwrap();
+        let finished: Vec<JobId> = state
+            .jobs
+            .values()
+            .filter(|job| job.finished_at.is_some())
+            .map(|job| job.id)
+            .collect();
+
+        for id in finished.iter().rev().skip(FINISHED_JOBS_KEPT) {
+            state.jobs.remove(id);
+        }
+    }
+
+    fn update(&self, batch: &Batch, mut f: impl FnMut(&mut Job)) {
+        let mut state = self.state.lock().unwrap();
+
+        for id in &batch.jobs {
+            if let Some(job) = state.jobs.get_mut(id) {
+                f(job);
+            }
+        }
+    }
+}
+
+impl Batch {
+    /// Check whether the batch has no jobs and only covers some tenants, so there is nothing to run
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty() && self.scopes.is_some()
+    }
+
+    /// Check whether the batch covers every tenant, as scheduled cycles do
+    pub fn is_full(&self) -> bool {
+        self.scopes.is_none()
+    }
+
+    /// Check whether the batch covers a tenant
+    pub fn includes(&self, tenant: &Tenant) -> bool {
+        self.scopes
+            .as_ref()
+            .is_none_or(|scopes| scopes.iter().any(|scope| scope.includes(tenant)))
+    }
+}
+
+impl Scope {
+    /// Check whether the scope covers every tenant
+    pub fn is_all(&self) -> bool {
+        self.cluster.is_none() && self.tenant.is_none()
+    }
+
+    /// Check whether the scope covers a tenant
+    pub fn includes(&self, tenant: &Tenant) -> bool {
+        self.cluster.as_ref().is_none_or(|c| *c == tenant.cluster)
+            && self.tenant.as_ref().is_none_or(|t| *t == tenant.id)
+    }
+
+    /// Check whether the scope covers every tenant another scope does
+    fn covers(&self, other: &Scope) -> bool {
+        let covers =
+            |this: &Option<String>, other: &Option<String>| this.is_none() || this == other;
+
+        covers(&self.cluster, &other.cluster) && covers(&self.tenant, &other.tenant)
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.cluster, &self.tenant) {
+            (Some(cluster), Some(tenant)) => {
+                write!(f, "Tenant '{}' in cluster '{}'", tenant, cluster)
+            }
+            (None, Some(tenant)) => write!(f, "Tenant '{}'", tenant),
+            (Some(cluster), None) => write!(f, "Cluster '{}'", cluster),
+            (None, None) => write!(f, "Every tenant"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(cluster: Option<&str>, tenant: Option<&str>) -> Scope {
+        Scope {
+            cluster: cluster.map(str::to_string),
+            tenant: tenant.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn requests_join_jobs_covering_them() {
+        let queue = JobQueue::default();
+
+        let tenant = queue.submit(scope(Some("main"), Some("prod")));
+        assert_eq!(
+            queue.submit(scope(Some("main"), Some("prod"))).id,
+            tenant.id
+        );
+
+        // A wider scope isn't covered by the tenant's job, but covers later requests
+        let cluster = queue.submit(scope(Some("main"), None));
+        assert_ne!(cluster.id, tenant.id);
+        assert_eq!(
+            queue.submit(scope(Some("main"), Some("dev"))).id,
+            cluster.id
+        );
+        assert_ne!(queue.submit(scope(Some("other"), None)).id, cluster.id);
+
+        let all = queue.submit(Scope::default());
+        assert_eq!(queue.submit(scope(Some("third"), Some("prod"))).id, all.id);
+    }
+
+    #[test]
+    fn running_jobs_are_joined_until_they_finish() {
+        let queue = JobQueue::default();
+
+        let job = queue.submit(scope(Some("main"), None));
+        let batch = queue.start(false);
+        assert_eq!(queue.get(job.id).unwrap().status, JobStatus::Running);
+        assert_eq!(queue.submit(scope(Some("main"), Some("prod"))).id, job.id);
+
+        queue.finish(&batch, None);
+        assert_eq!(queue.get(job.id).unwrap().status, JobStatus::Completed);
+        assert_ne!(queue.submit(scope(Some("main"), Some("prod"))).id, job.id);
+    }
+
+    #[test]
+    fn batches_cover_the_scopes_of_their_jobs() {
+        let queue = JobQueue::default();
+        let prod = Tenant::new("main", "prod");
+        let dev = Tenant::new("main", "dev");
+
+        queue.submit(scope(Some("main"), Some("prod")));
+        let batch = queue.start(false);
+        assert!(!batch.is_full());
+        assert!(batch.includes(&prod));
+        assert!(!batch.includes(&dev));
+
+        queue.submit(scope(Some("main"), Some("prod")));
+        queue.submit(Scope::default());
+        let batch = queue.start(false);
+        assert!(batch.is_full());
+        assert!(batch.includes(&dev));
+
+        let batch = queue.start(false);
+        assert!(batch.is_empty());
+        assert!(queue.start(true).is_full());
+    }
+
+    #[test]
+    fn jobs_for_undiscovered_tenants_fail() {
+        let queue = JobQueue::default();
+        let missing = scope(Some("main"), Some("missing"));
+        assert!(queue.knows(&missing));
+
+        let not_found = queue.submit(missing.clone());
+        let found = queue.submit(scope(Some("main"), None));
+        let batch = queue.start(false);
+        queue.discovered(&batch, &["main"], &[Tenant::new("main", "prod")]);
+
+        assert_eq!(queue.get(found.id).unwrap().tenants, 1);
+        let not_found = queue.get(not_found.id).unwrap();
+        assert_eq!(not_found.status, JobStatus::Failed);
+        assert!(not_found.error.is_some());
+
+        assert!(!queue.knows(&missing));
+        assert!(queue.knows(&scope(None, Some("prod"))));
+        assert!(queue.knows(&Scope::default()));
+    }
+}
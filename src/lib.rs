@@ -1,37 +1,95 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 use tokio::{
     select,
     signal::unix::{SignalKind, signal},
 };
 
+pub mod aggregation;
 pub mod config;
+pub mod drop_rules;
 pub mod exporter;
 pub mod grafana;
+pub mod history;
 pub mod http;
+pub mod jobs;
 pub mod metrics;
 pub mod mimir;
+pub mod promql;
+pub mod ratelimit;
+pub mod report;
+pub mod results;
+pub mod usage;
 
 #[derive(Parser, Debug, Clone, Default)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
-    /// Config file
-    #[arg(short, long)]
-    pub config: PathBuf,
+    #[command(subcommand)]
+    pub command: Option<Command>,
 
-    /// Output directory for intermediate files (grafana.json, prometheus-metrics.json)
-    #[arg(short, long, default_value = ".")]
+    /// Config file (required by `serve` and `analyze`)
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Output directory for mimirtool intermediate files and generated drop rules
+    #[arg(short, long, global = true, default_value = ".")]
     pub output_dir: PathBuf,
 
     /// Interval in seconds between analyses (default: 86400 = 24h)
-    #[arg(short, long, default_value = "86400")]
+    #[arg(short, long, global = true, default_value = "86400")]
     pub interval: u64,
 
+    /// Seconds to keep exporting metrics and tenants that disappeared between cycles, overriding
+    /// `persistence.staleRetention`
+    #[arg(long, global = true)]
+    pub stale_retention: Option<u64>,
+
     /// Disable analysis of alert rules
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub disable_alert_correlation: bool,
 }
 
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Run analyses on an interval and serve the results over HTTP (default)
+    Serve,
+    /// Run analyses on an interval and print the results after each one
+    Analyze(AnalyzeArgs),
+    /// Print the latest results of a running analyzer
+    Report(ReportArgs),
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct AnalyzeArgs {
+    /// Run a single analysis and exit, with a non-zero status if any tenant failed
+    #[arg(long)]
+    pub once: bool,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ReportArgs {
+    /// Base URL of the analyzer's HTTP server
+    #[arg(long, default_value = "http://localhost:8080")]
+    pub url: String,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct OutputArgs {
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t)]
+    pub format: report::Format,
+
+    /// File to write the results to, instead of standard output
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+}
+
 /// Handle signals
 pub fn signal_handler() {
     tokio::spawn(async move {
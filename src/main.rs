@@ -1,33 +1,45 @@
 use clap::Parser;
-use mimir_cardinality_analyzer::{Args, config, exporter::Exporter, http, metrics, signal_handler};
+use mimir_cardinality_analyzer::{
+    AnalyzeArgs, Args, Command, OutputArgs, ReportArgs, config, exporter::Exporter, http,
+    jobs::Batch, metrics, report, results::RESULTS, signal_handler,
+};
+use std::{sync::Arc, time::Duration};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Setup tracing
+    // Parse args
+    let args = Args::parse();
+
+    // Setup tracing, logging to stderr so results can be printed to stdout
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
         .init();
 
     // Register metrics
     metrics::register_metrics();
 
-    // Parse config
-    let args = Args::parse();
-    let config = config::Config::new(args)?;
-
     // Handle signals
     signal_handler();
 
+    match args.command.clone().unwrap_or(Command::Serve) {
+        Command::Serve => serve(config::Config::new(args)?).await,
+        Command::Analyze(analyze_args) => analyze(config::Config::new(args)?, analyze_args).await,
+        Command::Report(report_args) => report(report_args).await,
+    }
+}
+
+/// Run the exporter loop and the HTTP server
+async fn serve(config: config::Config) -> anyhow::Result<()> {
     // Create and start exporter
     tokio::spawn({
         let config = config.clone();
 
         async move {
-            let exporter = Exporter::new(config).unwrap();
+            let exporter = Arc::new(Exporter::new(config).unwrap());
             exporter.start().await.unwrap();
         }
     });
@@ -37,3 +49,59 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Run analyses and print the results after each one
+async fn analyze(mut config: config::Config, args: AnalyzeArgs) -> anyhow::Result<()> {
+    // A single analysis has nothing to carry over, so don't leave results and history behind
+    if args.once {
+        config.persistence.enabled = false;
+        config.history.enabled = false;
+    }
+
+    let interval = Duration::from_secs(config.cli.interval);
+    let exporter = Arc::new(Exporter::new(config)?);
+
+    loop {
+        let failed = match exporter.run_once(&Batch::default()).await {
+            Ok(failed) => failed,
+            Err(e) if args.once => return Err(e),
+            // Already logged, try again on the next cycle
+            Err(_) => {
+                tokio::time::sleep(interval).await;
+                continue;
+            }
+        };
+
+        write_output(&args.output, &report::rows(&RESULTS))?;
+
+        if args.once {
+            if !failed.is_empty() {
+                let failed: Vec<String> = failed.iter().map(|t| t.to_string()).collect();
+                anyhow::bail!("Failed to analyze tenants: {}", failed.join(", "));
+            }
+
+            return Ok(());
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Print the latest results of a running analyzer
+async fn report(args: ReportArgs) -> anyhow::Result<()> {
+    let rows = report::fetch(&args.url).await?;
+
+    write_output(&args.output, &rows)
+}
+
+/// Write rows to the output file, or standard output
+fn write_output(args: &OutputArgs, rows: &[report::Row]) -> anyhow::Result<()> {
+    let output = report::render(args.format, rows)?;
+
+    match &args.output {
+        Some(path) => std::fs::write(path, output)?,
+        None => print!("{}", output),
+    }
+
+    Ok(())
+}
@@ -1,5 +1,11 @@
-use crate::metrics::Status;
+use crate::{
+    metrics::Status,
+    mimir::tenant::Tenant,
+    results::{MetricStatus, ResultStore},
+    usage::Usage,
+};
 use metrics::{counter, describe_counter, describe_gauge, gauge};
+use std::{collections::BTreeMap, fmt::Write};
 
 /// Register the metrics for the application
 pub(super) fn register_metrics() {
@@ -21,10 +27,10 @@ pub(super) fn register_metrics() {
         "Timestamp of the last successful analysis cycle"
     );
 
-    // Gauge to track whether a given metric is active (1) or inactive (0). Should be labeled with the metric name and tenant.
-    describe_gauge!(
-        "metric_active",
-        "Tracks whether a given metric is active (1) or inactive (0)"
+    // Count of PromQL expressions that could not be parsed. Should be labeled with the source.
+    describe_counter!(
+        "promql_parse_failures_total",
+        "Total number of PromQL expressions that could not be parsed"
     );
 }
 
@@ -32,10 +38,13 @@ pub(super) fn register_metrics() {
 pub fn record_analysis_error(failure: TaskFailure) {
     match failure {
         TaskFailure::Cycle => counter!("analysis_errors_total", "task" => "cycle").increment(1),
-        TaskFailure::Tenant(tenant_id) => {
-            counter!("analysis_errors_total", "task" => "tenant", "tenant" => tenant_id)
-                .increment(1)
-        }
+        TaskFailure::Tenant(tenant) => counter!(
+            "analysis_errors_total",
+            "task" => "tenant",
+            "mimir_cluster" => tenant.cluster,
+            "tenant" => tenant.id
+        )
+        .increment(1),
     }
 }
 
@@ -49,9 +58,9 @@ pub fn record_analysis_cycle(status: Status) {
     }
 }
 
-/// Record the number of tenants discovered
-pub fn record_tenants_discovered(count: u64) {
-    gauge!("tenants_discovered_total").set(count as f64);
+/// Record the number of tenants discovered in a cluster
+pub fn record_tenants_discovered(cluster: &str, count: u64) {
+    gauge!("tenants_discovered_total", "mimir_cluster" => cluster.to_string()).set(count as f64);
 }
 
 /// Record the timestamp of the last successful analysis cycle
@@ -61,16 +70,276 @@ pub fn record_successful_analysis() {
     gauge!("last_successful_analysis_timestamp").set(timestamp);
 }
 
-/// Create usage metric for a given metric name
-pub fn set_metric(metric_name: &str, tenant_id: &str, active: bool) {
-    gauge!("metric_active", "metric" => metric_name.to_string(), "tenant" => tenant_id.to_string())
-        .set(if active { 1 } else { 0 });
+/// Record a PromQL expression that could not be parsed
+pub fn record_parse_failure(source: ExpressionSource) {
+    counter!("promql_parse_failures_total", "source" => source.to_string()).increment(1);
+}
+
+/// Render the per-metric analysis results in the Prometheus text format.
+///
+/// These are kept out of the metrics recorder, which can't remove series, so that metrics and tenants that
+/// disappear between cycles stop being exported.
+pub fn render_results(store: &ResultStore) -> String {
+    let mut active = String::new();
+    let mut series_count = String::new();
+    let mut usage_depth = String::new();
+    let mut usage_references = String::new();
+    let mut aggregation_reduction = String::new();
+    let mut unused_labels = String::new();
+    let mut label_values = String::new();
+    let mut unused_series = String::new();
+    let mut result_age = String::new();
+    let mut complete = String::new();
+    let mut first_seen = String::new();
+    let mut unused_since = String::new();
+    let mut growth = String::new();
+    let mut exploding = String::new();
+    let now = chrono::Utc::now();
+
+    store.visit(|view| {
+        let tenant = format!(
+            "mimir_cluster=\"{}\",tenant=\"{}\"",
+            escape_label_value(&view.tenant.cluster),
+            escape_label_value(&view.tenant.id)
+        );
+
+        let metrics = view
+            .result
+            .metrics
+            .iter()
+            .map(|(metric, result)| (metric.as_str(), result))
+            .chain(view.disappeared_metrics.iter().copied());
+
+        for (metric, result) in metrics {
+            let labels = format!("metric=\"{}\",{}", escape_label_value(metric), tenant);
+
+            // Metrics within the grace period are neither active nor flagged as unused yet, and
+            // metrics of incomplete analyses are unknown rather than unused
+            let value = match result.status() {
+                MetricStatus::Used => Some(1),
+                MetricStatus::Unused => Some(0),
+                MetricStatus::Unknown => Some(-1),
+                MetricStatus::New => None,
+            };
+
+            if let Some(value) = value {
+                let _ = writeln!(active, "metric_active{{{}}} {}", labels, value);
+            }
+
+            if let Some(at) = result.first_seen {
+                let _ = writeln!(
+                    first_seen,
+                    "metric_first_seen_timestamp{{{}}} {}",
+                    labels,
+                    at.timestamp()
+                );
+            }
+
+            for (window, ratio) in result.growth.windows() {
+                let _ = writeln!(
+                    growth,
+                    "metric_series_growth_ratio{{{},window=\"{}\"}} {}",
+                    labels, window, ratio
+                );
+            }
+
+            if result.exploding {
+                let _ = writeln!(exploding, "metric_exploding{{{}}} 1", labels);
+            }
+
+            if let Some(at) = result.unused_since {
+                let _ = writeln!(
+                    unused_since,
+                    "metric_unused_since_timestamp{{{}}} {}",
+                    labels,
+                    at.timestamp()
+                );
+            }
+
+            let _ = writeln!(
+                series_count,
+                "metric_series_count{{{}}} {}",
+                labels, result.series_count
+            );
+
+            if let Some(usage) = &result.usage {
+                let (used_via, depth) = match usage {
+                    Usage::Direct => ("direct", 0),
+                    Usage::Via { record, depth } => (record.as_str(), *depth),
+                };
+
+                let _ = writeln!(
+                    usage_depth,
+                    "metric_usage_depth{{{},used_via=\"{}\"}} {}",
+                    labels,
+                    escape_label_value(used_via),
+                    depth
+                );
+            }
+
+            // Sources found in Grafana are counted per org
+            let mut references: BTreeMap<(String, Option<&str>), usize> = BTreeMap::new();
+
+            for source in &result.sources {
+                *references
+                    .entry((source.kind().to_string(), source.org()))
+                    .or_default() += 1;
+            }
+
+            for label in result.labels.iter().filter(|label| !label.used) {
+                let label_labels =
+                    format!("{},label=\"{}\"", labels, escape_label_value(&label.name));
+
+                let _ = writeln!(unused_labels, "label_unused{{{}}} 1", label_labels);
+                let _ = writeln!(
+                    label_values,
+                    "label_values_count{{{}}} {}",
+                    label_labels, label.values_count
+                );
+            }
+
+            if let Some(recommendation) = &result.recommendation {
+                let _ = writeln!(
+                    aggregation_reduction,
+                    "aggregation_estimated_series_reduction{{{}}} {}",
+                    labels, recommendation.estimated_reduction
+                );
+            }
+
+            for ((source, org), count) in references {
+                let org = org
+                    .map(|org| format!(",org=\"{}\"", escape_label_value(org)))
+                    .unwrap_or_default();
+
+                let _ = writeln!(
+                    usage_references,
+                    "metric_usage_references{{{},source=\"{}\"{}}} {}",
+                    labels, source, org, count
+                );
+            }
+        }
+
+        let _ = writeln!(
+            unused_series,
+            "unused_series_total{{{}}} {}",
+            tenant,
+            view.result.unused_series()
+        );
+        let _ = writeln!(
+            result_age,
+            "result_age_seconds{{{}}} {}",
+            tenant,
+            (now - view.result.analyzed_at).num_seconds()
+        );
+        let _ = writeln!(
+            complete,
+            "analysis_complete{{{}}} {}",
+            tenant,
+            u8::from(view.result.is_complete())
+        );
+    });
+
+    let families = [
+        (
+            "metric_active",
+            "Tracks whether a given metric is active (1), inactive (0) or unknown (-1)",
+            active,
+        ),
+        (
+            "metric_series_count",
+            "Number of active series for a given metric",
+            series_count,
+        ),
+        (
+            "metric_first_seen_timestamp",
+            "Timestamp of the cycle a metric was first seen in",
+            first_seen,
+        ),
+        (
+            "metric_unused_since_timestamp",
+            "Timestamp of the cycle since which a metric has been unused",
+            unused_since,
+        ),
+        (
+            "metric_series_growth_ratio",
+            "Ratio of a metric's series count to its series count a day or a week before",
+            growth,
+        ),
+        (
+            "metric_exploding",
+            "Metrics whose series count grew past the exploding threshold",
+            exploding,
+        ),
+        (
+            "metric_usage_depth",
+            "Number of recording rules between a used metric and a dashboard, alert or alerting rule (0 if used directly)",
+            usage_depth,
+        ),
+        (
+            "metric_usage_references",
+            "Number of dashboard panels, alerts or rules referencing a metric, including recording rules it is used through",
+            usage_references,
+        ),
+        (
+            "aggregation_estimated_series_reduction",
+            "Minimum number of series removed by aggregating a metric down to the labels its queries use",
+            aggregation_reduction,
+        ),
+        (
+            "label_unused",
+            "Labels of a used metric that no query depends on",
+            unused_labels,
+        ),
+        (
+            "label_values_count",
+            "Number of values of a label of a used metric that no query depends on",
+            label_values,
+        ),
+        (
+            "unused_series_total",
+            "Total number of active series belonging to metrics that are not in use",
+            unused_series,
+        ),
+        (
+            "result_age_seconds",
+            "Seconds since the exported results of a tenant were analyzed",
+            result_age,
+        ),
+        (
+            "analysis_complete",
+            "Whether every usage source of a tenant's latest analysis loaded (1) or not (0)",
+            complete,
+        ),
+    ];
+
+    let mut output = String::new();
+
+    for (name, help, samples) in families {
+        if samples.is_empty() {
+            continue;
+        }
+
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} gauge", name);
+        output.push_str(&samples);
+        output.push('\n');
+    }
+
+    output
+}
+
+/// Escape a label value for the Prometheus text format
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
 }
 
 #[derive(Debug, Clone)]
 pub enum TaskFailure {
     Cycle,
-    Tenant(String),
+    Tenant(Tenant),
 }
 
 impl std::fmt::Display for TaskFailure {
@@ -81,3 +350,20 @@ impl std::fmt::Display for TaskFailure {
         }
     }
 }
+
+#[derive(Debug, Clone)]
+pub enum ExpressionSource {
+    Dashboard,
+    Alert,
+    Rule,
+}
+
+impl std::fmt::Display for ExpressionSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExpressionSource::Dashboard => write!(f, "dashboard"),
+            ExpressionSource::Alert => write!(f, "alert"),
+            ExpressionSource::Rule => write!(f, "rule"),
+        }
+    }
+}
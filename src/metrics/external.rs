@@ -1,5 +1,5 @@
 use crate::metrics::Status;
-use metrics::{counter, describe_counter, describe_histogram, histogram};
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
 use std::time::Instant;
 
 /// Register the metrics for the application
@@ -27,6 +27,18 @@ pub(super) fn register_metrics() {
         "mimirtool_duration_seconds",
         "Duration of mimirtool executions in seconds"
     );
+
+    // Whether the last analysis of a Grafana instance succeeded, labeled by the instance.
+    describe_gauge!(
+        "grafana_fetch_success",
+        "Whether the dashboards and alert rules of a Grafana instance were fetched in the last cycle"
+    );
+
+    // Timestamp of the last successful analysis of a Grafana instance, labeled by the instance.
+    describe_gauge!(
+        "grafana_fetch_last_success_timestamp",
+        "Timestamp of the last successful fetch of a Grafana instance"
+    );
 }
 
 /// Record an external request failure for a given target
@@ -34,6 +46,29 @@ pub fn record_external_request_failure(target: Target) {
     counter!("external_request_failures_total", "target" => target.to_string()).increment(1);
 }
 
+/// Record a failed request to a target of a Mimir cluster
+pub fn record_cluster_request_failure(target: Target, cluster: &str) {
+    counter!("external_request_failures_total", "target" => target.to_string(), "mimir_cluster" => cluster.to_string())
+        .increment(1);
+}
+
+/// Record the outcome of fetching the dashboards and alert rules of a Grafana instance
+pub fn record_grafana_fetch(grafana: &str, status: Status) {
+    let success = match status {
+        Status::Success => 1.0,
+        Status::Failure => 0.0,
+    };
+
+    gauge!("grafana_fetch_success", "grafana" => grafana.to_string()).set(success);
+
+    if let Status::Success = status {
+        let timestamp = chrono::Utc::now().timestamp() as f64;
+
+        gauge!("grafana_fetch_last_success_timestamp", "grafana" => grafana.to_string())
+            .set(timestamp);
+    }
+}
+
 /// Create a timer for an external request to a given target
 pub fn external_request_timer(target: Target) -> Timer {
     Timer::new("external_request_duration_seconds").with_label("target", target.to_string())
@@ -94,6 +129,7 @@ impl Drop for Timer {
 pub enum Target {
     StoreGateway,
     Querier,
+    Ruler,
     Grafana,
 }
 
@@ -102,6 +138,7 @@ impl std::fmt::Display for Target {
         match self {
             Target::StoreGateway => write!(f, "store-gateway"),
             Target::Querier => write!(f, "querier"),
+            Target::Ruler => write!(f, "ruler"),
             Target::Grafana => write!(f, "grafana"),
         }
     }
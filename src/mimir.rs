@@ -1,43 +1,84 @@
 use crate::{
-    config::Config,
+    config::{Config, Coverage, Mimir as MimirConfig},
     metrics::{
         self,
+        analysis::ExpressionSource,
         external::{Command as ExternalCommand, Target},
     },
+    mimir::{
+        cardinality::{Cardinality, LabelNameCardinality},
+        rules::Namespaces,
+        tenant::Tenant,
+    },
+    promql::MetricReferences,
+    ratelimit::RateLimiters,
+    usage::{RecordingRule, RuleUsage, Source},
 };
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use scraper::{Html, Selector};
+use std::collections::HashMap;
 use tokio::process::Command;
 
 pub mod cardinality;
+pub mod label;
+pub mod rules;
+pub mod tenant;
+
+/// Maximum number of labels returned per metric by the label names cardinality API
+const LABEL_NAMES_LIMIT: usize = 500;
+
+/// Maximum number of metric names listed per request
+const METRIC_NAMES_PAGE_SIZE: usize = 10_000;
 
+/// Characters a metric name can start with, and the ones it can contain, in byte order
+const NAME_START_CHARS: &str = ":ABCDEFGHIJKLMNOPQRSTUVWXYZ_abcdefghijklmnopqrstuvwxyz";
+const NAME_CHARS: &str = "0123456789:ABCDEFGHIJKLMNOPQRSTUVWXYZ_abcdefghijklmnopqrstuvwxyz";
+
+/// Client of a single Mimir cluster
 pub struct Mimir {
     config: Config,
+    cluster: MimirConfig,
     client: Client,
+    limits: RateLimiters,
 }
 
 impl Mimir {
     /// Create a new Mimir instance
-    pub fn new(config: Config) -> Self {
+    pub fn new(config: Config, cluster: MimirConfig, limits: RateLimiters) -> Self {
         let client = Client::new();
 
-        Self { config, client }
+        Self {
+            config,
+            cluster,
+            client,
+            limits,
+        }
+    }
+
+    /// Name of the cluster
+    pub fn name(&self) -> &str {
+        &self.cluster.name
+    }
+
+    /// The coverage of the cluster's analysis
+    pub fn coverage(&self) -> &Coverage {
+        &self.cluster.coverage
     }
 
     /// Get a list of tenants from the store-gateway
-    pub async fn get_tenants(&self) -> anyhow::Result<Vec<String>> {
+    #[tracing::instrument(skip(self), fields(cluster = %self.name()))]
+    pub async fn get_tenants(&self) -> anyhow::Result<Vec<Tenant>> {
         tracing::info!("Fetching tenants from store-gateway");
-        let _timer = metrics::external::external_request_timer(Target::StoreGateway);
+        self.limits.acquire(Target::StoreGateway).await;
+        let _timer = metrics::external::external_request_timer(Target::StoreGateway)
+            .with_label("mimir_cluster", self.name());
 
-        let url = format!(
-            "{}/store-gateway/tenants",
-            self.config.mimir.store_gateway_url
-        );
+        let url = format!("{}/store-gateway/tenants", self.cluster.store_gateway_url);
 
         let resp = self.client.get(&url).send().await?;
 
         if !resp.status().is_success() {
-            metrics::external::record_external_request_failure(Target::StoreGateway);
+            metrics::external::record_cluster_request_failure(Target::StoreGateway, self.name());
 
             return Err(anyhow::anyhow!(
                 "Failed to fetch tenants: HTTP {}",
@@ -51,38 +92,49 @@ impl Mimir {
             .map_err(|e| anyhow::anyhow!("Failed to parse HTML selector: {}", e))?;
 
         // Get all the tenant names
-        let tenants: Vec<String> = document
+        let tenants: Vec<Tenant> = document
             .select(&selector)
-            .filter_map(|element| Some(element.text().next()?.to_string()))
+            .filter_map(|element| Some(Tenant::new(self.name(), element.text().next()?)))
             .collect();
 
         // Record the number of tenants discovered
-        metrics::analysis::record_tenants_discovered(tenants.len() as u64);
+        metrics::analysis::record_tenants_discovered(self.name(), tenants.len() as u64);
 
         Ok(tenants)
     }
 
-    /// Analyze Grafana instance
+    /// Analyze Grafana instance. The result doesn't depend on the cluster, so this only needs to
+    /// run on one.
     #[tracing::instrument(skip(self))]
     pub async fn analyze_grafana(&self) -> anyhow::Result<()> {
         tracing::info!("Analyzing metric usage in dashboards");
+        self.limits.acquire(Target::Grafana).await;
         let _timer = metrics::external::mimirtool_timer(ExternalCommand::AnalyzeGrafana);
 
         let grafana_output = self.config.output_dir.join("grafana.json");
         let grafana_output = grafana_output.to_string_lossy();
 
+        // The config only allows mimirtool with a single Grafana instance
+        let grafana = &self.config.grafana[0];
+
         let args = vec![
             "analyze",
             "grafana",
             "--address",
-            &self.config.grafana.url,
+            &grafana.url,
             "--key",
-            &self.config.grafana.token,
+            &grafana.token,
             "--output",
             &grafana_output,
         ];
 
-        match Command::new("mimirtool").args(args).output().await {
+        // Killed if the analysis is abandoned, such as when a tenant times out
+        let output = Command::new("mimirtool")
+            .args(args)
+            .kill_on_drop(true)
+            .output();
+
+        match output.await {
             Ok(output) => {
                 if !output.status.success() {
                     metrics::external::record_mimirtool_execution(
@@ -117,23 +169,30 @@ impl Mimir {
     }
 
     /// Analyze tenant in Mimir
-    #[tracing::instrument(skip(self))]
-    pub async fn analyze_tenant(&self, tenant_id: &str) -> anyhow::Result<Vec<String>> {
+    #[tracing::instrument(skip(self), fields(cluster = %self.name()))]
+    pub async fn analyze_tenant(&self, tenant_id: &str) -> anyhow::Result<MetricReferences> {
         tracing::info!("Analyzing metric cardinality in Mimir");
+        self.limits.acquire(Target::Querier).await;
         let _timer = metrics::external::mimirtool_timer(ExternalCommand::AnalyzePrometheus)
+            .with_label("mimir_cluster", self.name())
             .with_label("tenant", tenant_id);
 
         let grafana_input = self.config.output_dir.join("grafana.json");
         let grafana_input = grafana_input.to_string_lossy();
 
-        let prometheus_output = self.config.output_dir.join("prometheus-metrics.json");
-        let prometheus_output = prometheus_output.to_string_lossy();
+        // Each tenant writes its own file, so tenants can be analyzed concurrently
+        let prometheus_path = self.config.output_dir.join(format!(
+            "prometheus-metrics-{}-{}.json",
+            self.name(),
+            tenant_id
+        ));
+        let prometheus_output = prometheus_path.to_string_lossy();
 
         let args = vec![
             "analyze",
             "prometheus",
             "--address",
-            &self.config.mimir.querier_url,
+            &self.cluster.querier_url,
             "--id",
             tenant_id,
             "--prometheus-http-prefix",
@@ -144,7 +203,13 @@ impl Mimir {
             &prometheus_output,
         ];
 
-        match Command::new("mimirtool").args(args).output().await {
+        // Killed if the analysis is abandoned, such as when a tenant times out
+        let output = Command::new("mimirtool")
+            .args(args)
+            .kill_on_drop(true)
+            .output();
+
+        match output.await {
             Ok(output) => {
                 if !output.status.success() {
                     metrics::external::record_mimirtool_execution(
@@ -170,8 +235,7 @@ impl Mimir {
             }
         };
 
-        let content =
-            std::fs::read_to_string(self.config.output_dir.join("prometheus-metrics.json"))?;
+        let content = tokio::fs::read_to_string(&prometheus_path).await?;
 
         let data: serde_json::Value = serde_json::from_str(&content)?;
 
@@ -190,25 +254,33 @@ impl Mimir {
         Ok(metrics)
     }
 
-    /// Gets the top 100 metrics by cardinality for a tenant
-    pub async fn get_tenant_top_metrics(&self, tenant_id: &str) -> anyhow::Result<Vec<String>> {
+    /// Gets the top metrics by cardinality for a tenant
+    pub async fn get_tenant_top_metrics(
+        &self,
+        tenant_id: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Cardinality>> {
         let url = format!(
-            "{}/prometheus/api/v1/cardinality/label_values?label_names[]=__name__&limit=100",
-            self.config.mimir.querier_url
+            "{}/prometheus/api/v1/cardinality/label_values",
+            self.cluster.querier_url
         );
 
+        self.limits.acquire(Target::Querier).await;
+
         let _timer = metrics::external::external_request_timer(Target::Querier)
+            .with_label("mimir_cluster", self.name())
             .with_label("tenant", tenant_id);
 
         let resp = self
             .client
             .get(&url)
+            .query(&[("label_names[]", "__name__"), ("limit", &limit.to_string())])
             .header("X-Scope-OrgID", tenant_id)
             .send()
             .await?;
 
         if !resp.status().is_success() {
-            metrics::external::record_external_request_failure(Target::Querier);
+            metrics::external::record_cluster_request_failure(Target::Querier, self.name());
 
             return Err(anyhow::anyhow!(
                 "Failed to fetch tenant metrics: HTTP {}",
@@ -218,12 +290,325 @@ impl Mimir {
 
         let json = resp.json::<cardinality::Response>().await?;
 
-        let metrics: Vec<String> = json
+        let metrics: Vec<Cardinality> = json
             .labels
             .into_iter()
-            .flat_map(|label| label.cardinality.into_iter().map(|card| card.label_value))
+            .flat_map(|label| label.cardinality)
             .collect();
 
         Ok(metrics)
     }
+
+    /// Gets all metric names for a tenant, in order and truncated to `limit` if set.
+    ///
+    /// Names are listed in pages of at most `METRIC_NAMES_PAGE_SIZE`. The API can't skip values,
+    /// so a full page is listed again split by the next character of the names, until every page
+    /// fits. Prefixes are listed in order, so paging stops once `limit` names are found.
+    pub async fn get_tenant_metric_names(
+        &self,
+        tenant_id: &str,
+        limit: Option<usize>,
+    ) -> anyhow::Result<Vec<String>> {
+        let limit = limit.unwrap_or(usize::MAX);
+        let mut names = Vec::new();
+        let mut prefixes = vec![String::new()];
+
+        while let Some(prefix) = prefixes.pop() {
+            if names.len() >= limit {
+                break;
+            }
+
+            let mut page = self.get_metric_names_page(tenant_id, &prefix).await?;
+
+            if page.len() < METRIC_NAMES_PAGE_SIZE {
+                page.sort();
+                page.truncate(limit - names.len());
+                names.extend(page);
+                continue;
+            }
+
+            // Splitting the prefix leaves out the name equal to it
+            if page.contains(&prefix) {
+                names.push(prefix.clone());
+            }
+
+            let chars = match prefix.is_empty() {
+                true => NAME_START_CHARS,
+                false => NAME_CHARS,
+            };
+
+            // In reverse, so the prefixes are listed in order
+            prefixes.extend(chars.chars().rev().map(|c| format!("{}{}", prefix, c)));
+        }
+
+        Ok(names)
+    }
+
+    /// Gets a page of the metric names of a tenant starting with a prefix
+    async fn get_metric_names_page(
+        &self,
+        tenant_id: &str,
+        prefix: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        let url = format!(
+            "{}/prometheus/api/v1/label/__name__/values",
+            self.cluster.querier_url
+        );
+
+        self.limits.acquire(Target::Querier).await;
+
+        let _timer = metrics::external::external_request_timer(Target::Querier)
+            .with_label("mimir_cluster", self.name())
+            .with_label("tenant", tenant_id);
+
+        let mut query = vec![("limit", METRIC_NAMES_PAGE_SIZE.to_string())];
+
+        if !prefix.is_empty() {
+            query.push((
+                "match[]",
+                format!(
+                    "{{__name__=~{}}}",
+                    quote(&format!("{}.*", regex::escape(prefix)))
+                ),
+            ));
+        }
+
+        let resp = self
+            .client
+            .get(&url)
+            .query(&query)
+            .header("X-Scope-OrgID", tenant_id)
+            .send()
+            .await?;
+
+        if !resp.status().is_success() {
+            metrics::external::record_cluster_request_failure(Target::Querier, self.name());
+
+            return Err(anyhow::anyhow!(
+                "Failed to fetch tenant metric names: HTTP {}",
+                resp.status()
+            ));
+        }
+
+        Ok(resp.json::<label::Response>().await?.data)
+    }
+
+    /// Gets the cardinality of a batch of metrics for a tenant.
+    ///
+    /// Metrics without any active series are returned with a series count of 0.
+    pub async fn get_metrics_cardinality(
+        &self,
+        tenant_id: &str,
+        metric_names: &[String],
+    ) -> anyhow::Result<Vec<Cardinality>> {
+        let url = format!(
+            "{}/prometheus/api/v1/cardinality/label_values",
+            self.cluster.querier_url
+        );
+
+        self.limits.acquire(Target::Querier).await;
+
+        let _timer = metrics::external::external_request_timer(Target::Querier)
+            .with_label("mimir_cluster", self.name())
+            .with_label("tenant", tenant_id);
+
+        // Metric names can't contain regex metacharacters, but escape them anyway
+        let selector = format!(
+            "{{__name__=~{}}}",
+            quote(
+                &metric_names
+                    .iter()
+                    .map(|name| regex::escape(name))
+                    .collect::<Vec<_>>()
+                    .join("|")
+            )
+        );
+
+        // POST, since the selector for a large batch can exceed URL length limits
+        let resp = self
+            .client
+            .post(&url)
+            .form(&[
+                ("label_names[]", "__name__"),
+                ("selector", &selector),
+                ("limit", &metric_names.len().to_string()),
+            ])
+            .header("X-Scope-OrgID", tenant_id)
+            .send()
+            .await?;
+
+        if !resp.status().is_success() {
+            metrics::external::record_cluster_request_failure(Target::Querier, self.name());
+
+            return Err(anyhow::anyhow!(
+                "Failed to fetch metric cardinality: HTTP {}",
+                resp.status()
+            ));
+        }
+
+        let json = resp.json::<cardinality::Response>().await?;
+
+        let mut counts: HashMap<String, usize> = json
+            .labels
+            .into_iter()
+            .flat_map(|label| label.cardinality)
+            .map(|card| (card.label_value, card.series_count))
+            .collect();
+
+        let metrics = metric_names
+            .iter()
+            .map(|name| Cardinality {
+                label_value: name.clone(),
+                series_count: counts.remove(name).unwrap_or(0),
+            })
+            .collect();
+
+        Ok(metrics)
+    }
+
+    /// Gets the labels of a metric in a tenant, with the number of values of each
+    pub async fn get_metric_label_names(
+        &self,
+        tenant_id: &str,
+        metric: &str,
+    ) -> anyhow::Result<Vec<LabelNameCardinality>> {
+        let url = format!(
+            "{}/prometheus/api/v1/cardinality/label_names",
+            self.cluster.querier_url
+        );
+
+        self.limits.acquire(Target::Querier).await;
+        let _timer = metrics::external::external_request_timer(Target::Querier)
+            .with_label("mimir_cluster", self.name())
+            .with_label("tenant", tenant_id);
+
+        let selector = format!("{{__name__={}}}", quote(metric));
+
+        let resp = self
+            .client
+            .get(&url)
+            .query(&[
+                ("selector", selector.as_str()),
+                ("limit", &LABEL_NAMES_LIMIT.to_string()),
+            ])
+            .header("X-Scope-OrgID", tenant_id)
+            .send()
+            .await?;
+
+        if !resp.status().is_success() {
+            metrics::external::record_cluster_request_failure(Target::Querier, self.name());
+
+            return Err(anyhow::anyhow!(
+                "Failed to fetch metric label names: HTTP {}",
+                resp.status()
+            ));
+        }
+
+        let labels = resp
+            .json::<cardinality::LabelNamesResponse>()
+            .await?
+            .cardinality
+            .into_iter()
+            .filter(|label| label.label_name != "__name__")
+            .collect();
+
+        Ok(labels)
+    }
+
+    /// Get the ruler rule groups of a tenant, by namespace
+    #[tracing::instrument(skip(self), fields(cluster = %self.name()))]
+    pub async fn get_rule_groups(&self, tenant_id: &str) -> anyhow::Result<Namespaces> {
+        let Some(ruler_url) = &self.cluster.ruler_url else {
+            return Ok(Namespaces::new());
+        };
+
+        let url = format!("{}/prometheus/config/v1/rules", ruler_url);
+
+        self.limits.acquire(Target::Ruler).await;
+
+        let _timer = metrics::external::external_request_timer(Target::Ruler)
+            .with_label("mimir_cluster", self.name())
+            .with_label("tenant", tenant_id);
+
+        let resp = self
+            .client
+            .get(&url)
+            .header("X-Scope-OrgID", tenant_id)
+            .send()
+            .await?;
+
+        // The ruler responds with 404 when a tenant has no rule groups
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(Namespaces::new());
+        }
+
+        if !resp.status().is_success() {
+            metrics::external::record_cluster_request_failure(Target::Ruler, self.name());
+
+            return Err(anyhow::anyhow!(
+                "Failed to fetch rule groups: HTTP {}",
+                resp.status()
+            ));
+        }
+
+        let body = resp.text().await?;
+
+        Ok(serde_norway::from_str(&body)?)
+    }
+
+    /// Get the metrics referenced by a tenant's ruler rules.
+    ///
+    /// Alerting rules consume metrics directly, while recording rules only do so if their output is used.
+    #[tracing::instrument(skip(self), fields(cluster = %self.name()))]
+    pub async fn analyze_rules(&self, tenant_id: &str) -> anyhow::Result<RuleUsage> {
+        let namespaces = self.get_rule_groups(tenant_id).await?;
+        let mut usage = RuleUsage::default();
+
+        for (namespace, groups) in &namespaces {
+            for group in groups {
+                for rule in &group.rules {
+                    let references = match MetricReferences::from_expr(&rule.expr) {
+                        Ok(references) => references,
+                        Err(e) => {
+                            tracing::warn!(
+                                "Failed to parse expression in rule group '{}': {}",
+                                group.name,
+                                e
+                            );
+                            metrics::analysis::record_parse_failure(ExpressionSource::Rule);
+                            usage.unparsed.push(format!(
+                                "unparsable expression in rule group '{}/{}': {}",
+                                namespace, group.name, e
+                            ));
+                            continue;
+                        }
+                    };
+
+                    let name = rule.record.as_ref().or(rule.alert.as_ref());
+
+                    let source = Source::Rule {
+                        namespace: namespace.clone(),
+                        group: group.name.clone(),
+                        rule: name.cloned().unwrap_or_default(),
+                    };
+
+                    match &rule.record {
+                        Some(record) => usage.recording.push(RecordingRule {
+                            record: record.clone(),
+                            source,
+                            references,
+                        }),
+                        None => usage.alerting.push((source, references)),
+                    }
+                }
+            }
+        }
+
+        Ok(usage)
+    }
+}
+
+/// Quote a label matcher value as a PromQL string
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
 }
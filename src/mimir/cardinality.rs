@@ -10,8 +10,20 @@ pub struct Label {
     pub cardinality: Vec<Cardinality>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Cardinality {
     pub label_value: String,
     pub series_count: usize,
 }
+
+/// Response of `/api/v1/cardinality/label_names`
+#[derive(Deserialize)]
+pub struct LabelNamesResponse {
+    pub cardinality: Vec<LabelNameCardinality>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct LabelNameCardinality {
+    pub label_name: String,
+    pub label_values_count: usize,
+}
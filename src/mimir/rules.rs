@@ -0,0 +1,23 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Rule groups per namespace, as returned by `/prometheus/config/v1/rules`
+pub type Namespaces = HashMap<String, Vec<RuleGroup>>;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RuleGroup {
+    pub name: String,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Rule {
+    /// Name of the series written by a recording rule
+    #[serde(default)]
+    pub record: Option<String>,
+    /// Name of an alerting rule
+    #[serde(default)]
+    pub alert: Option<String>,
+    pub expr: String,
+}
@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+/// A tenant of a Mimir cluster
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Tenant {
+    pub cluster: String,
+    #[serde(rename = "tenant")]
+    pub id: String,
+}
+
+impl Tenant {
+    pub fn new(cluster: impl Into<String>, id: impl Into<String>) -> Self {
+        Self {
+            cluster: cluster.into(),
+            id: id.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Tenant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.cluster, self.id)
+    }
+}
@@ -0,0 +1,449 @@
+use crate::promql::{
+    ast::{MatchOp, VectorSelector},
+    labels::{LabelUsage, selector_labels},
+    parser::parse,
+};
+use regex::Regex;
+use std::collections::HashMap;
+
+pub mod ast;
+pub mod labels;
+pub mod lexer;
+pub mod parser;
+
+/// Built-in Grafana interval variables, with their replacement: a duration of 5 minutes, or the
+/// same interval as a number for the variables holding seconds or milliseconds
+const INTERVAL_VARIABLES: &[(&str, &str)] = &[
+    ("__rate_interval", "5m"),
+    ("__rate_interval_ms", "300000"),
+    ("__interval", "5m"),
+    ("__interval_ms", "300000"),
+    ("__range", "5m"),
+    ("__range_s", "300"),
+    ("__range_ms", "300000"),
+];
+
+/// Placeholder substituted for template variables that aren't intervals
+const VARIABLE_PLACEHOLDER: &str = "__template_variable__";
+
+/// Metrics referenced by a set of PromQL expressions
+#[derive(Debug, Clone, Default)]
+pub struct MetricReferences {
+    /// Metrics referenced by name, with the labels used
+    names: HashMap<String, LabelUsage>,
+    /// Metrics referenced through regex `__name__` matchers, keyed by their matchers to avoid duplicates
+    patterns: HashMap<Vec<(MatchOp, String)>, NamePattern>,
+}
+
+/// The `__name__` matchers of a selector that doesn't reference a single metric by name
+#[derive(Debug, Clone)]
+struct NamePattern {
+    matchers: Vec<(MatchOp, Regex)>,
+    labels: LabelUsage,
+}
+
+impl MetricReferences {
+    /// Parse a PromQL expression into the metrics it references
+    pub fn from_expr(expr: &str) -> anyhow::Result<Self> {
+        let mut references = Self::default();
+        references.add_expr(expr)?;
+
+        Ok(references)
+    }
+
+    /// Parse a PromQL expression and add the metrics it references.
+    ///
+    /// Grafana template variables are interpolated before parsing.
+    pub fn add_expr(&mut self, expr: &str) -> anyhow::Result<()> {
+        let expr = parse(&interpolate(expr))?;
+
+        for (selector, labels) in selector_labels(&expr) {
+            self.add_selector(selector, labels)?;
+        }
+
+        Ok(())
+    }
+
+    /// Add the metrics matched by a selector
+    fn add_selector(
+        &mut self,
+        selector: &VectorSelector,
+        labels: LabelUsage,
+    ) -> anyhow::Result<()> {
+        // Names built from template variables can't be resolved
+        let matchers: Vec<_> = selector
+            .name_matchers()
+            .into_iter()
+            .filter(|m| !m.value.contains(VARIABLE_PLACEHOLDER))
+            .collect();
+
+        // Selectors without a positive name matcher, like `{job="foo"}`, don't reference specific metrics
+        if !matchers
+            .iter()
+            .any(|m| matches!(m.op, MatchOp::Equal | MatchOp::Regex))
+        {
+            return Ok(());
+        }
+
+        // The common case of a single metric name
+        if let [matcher] = matchers.as_slice()
+            && matcher.op == MatchOp::Equal
+        {
+            self.add_name(matcher.value.clone(), labels);
+            return Ok(());
+        }
+
+        let key: Vec<_> = matchers.iter().map(|m| (m.op, m.value.clone())).collect();
+
+        if let Some(pattern) = self.patterns.get_mut(&key) {
+            pattern.labels = pattern.labels.union(&labels);
+            return Ok(());
+        }
+
+        let matchers = matchers
+            .into_iter()
+            .map(|m| {
+                let regex = match m.op {
+                    MatchOp::Equal | MatchOp::NotEqual => regex::escape(&m.value),
+                    MatchOp::Regex | MatchOp::NotRegex => m.value.clone(),
+                };
+
+                // Label matcher regexes are fully anchored
+                Ok((m.op, Regex::new(&format!("^(?:{})$", regex))?))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        self.patterns.insert(key, NamePattern { matchers, labels });
+
+        Ok(())
+    }
+
+    /// Add a metric referenced by name
+    fn add_name(&mut self, name: String, labels: LabelUsage) {
+        match self.names.get_mut(&name) {
+            Some(existing) => *existing = existing.union(&labels),
+            None => {
+                self.names.insert(name, labels);
+            }
+        }
+    }
+
+    /// Add all metrics referenced by another set
+    pub fn extend(&mut self, other: MetricReferences) {
+        for (name, labels) in other.names {
+            self.add_name(name, labels);
+        }
+
+        for (key, pattern) in other.patterns {
+            match self.patterns.get_mut(&key) {
+                Some(existing) => existing.labels = existing.labels.union(&pattern.labels),
+                None => {
+                    self.patterns.insert(key, pattern);
+                }
+            }
+        }
+    }
+
+    /// Check whether a metric is referenced, either by name or through a pattern
+    pub fn contains(&self, metric: &str) -> bool {
+        self.names.contains_key(metric) || self.matches_pattern(metric)
+    }
+
+    /// Get the labels of a metric used by the references, if it is referenced
+    pub fn labels(&self, metric: &str) -> Option<LabelUsage> {
+        self.patterns
+            .values()
+            .filter(|pattern| pattern.is_match(metric))
+            .map(|pattern| &pattern.labels)
+            .chain(self.names.get(metric))
+            .cloned()
+            .reduce(|acc, labels| acc.union(&labels))
+    }
+
+    /// Check whether a metric is referenced through a pattern
+    pub fn matches_pattern(&self, metric: &str) -> bool {
+        self.patterns
+            .values()
+            .any(|pattern| pattern.is_match(metric))
+    }
+
+    /// Metrics referenced by name
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.names.keys().map(String::as_str)
+    }
+
+    /// Check whether any metric is referenced through a pattern
+    pub fn has_patterns(&self) -> bool {
+        !self.patterns.is_empty()
+    }
+
+    /// Number of metric names and patterns referenced
+    pub fn len(&self) -> usize {
+        self.names.len() + self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Metrics referenced by name, with unknown label usage
+impl FromIterator<String> for MetricReferences {
+    fn from_iter<T: IntoIterator<Item = String>>(iter: T) -> Self {
+        Self {
+            names: iter
+                .into_iter()
+                .map(|name| (name, LabelUsage::All))
+                .collect(),
+            patterns: HashMap::new(),
+        }
+    }
+}
+
+impl NamePattern {
+    fn is_match(&self, metric: &str) -> bool {
+        self.matchers.iter().all(|(op, regex)| match op {
+            MatchOp::Equal | MatchOp::Regex => regex.is_match(metric),
+            MatchOp::NotEqual | MatchOp::NotRegex => !regex.is_match(metric),
+        })
+    }
+}
+
+/// Replace Grafana template variables so the expression can be analyzed.
+///
+/// Interval variables become a fixed duration or number; other variables are replaced by a
+/// placeholder.
+pub fn interpolate(expr: &str) -> String {
+    let mut result = String::with_capacity(expr.len());
+    let mut rest = expr;
+
+    while let Some(pos) = rest.find(['$', '[']) {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+
+        // `[[var]]` syntax
+        if let Some(inner) = rest.strip_prefix("[[")
+            && let Some(end) = inner.find("]]")
+        {
+            result.push_str(replacement(&inner[..end]));
+            rest = &inner[end + 2..];
+            continue;
+        }
+
+        // `${var}` and `${var:format}` syntax
+        if let Some(inner) = rest.strip_prefix("${")
+            && let Some(end) = inner.find('}')
+        {
+            let name = inner[..end].split(':').next().unwrap_or_default();
+            result.push_str(replacement(name));
+            rest = &inner[end + 1..];
+            continue;
+        }
+
+        // `$var` syntax. Variable names can't contain colons, which may follow them as in
+        // `[$__range:$__interval]`.
+        if let Some(inner) = rest.strip_prefix('$') {
+            let end = inner
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(inner.len());
+
+            if end > 0 {
+                result.push_str(replacement(&inner[..end]));
+                rest = &inner[end..];
+                continue;
+            }
+        }
+
+        // Not a variable, keep the character as is
+        result.push_str(&rest[..1]);
+        rest = &rest[1..];
+    }
+
+    result.push_str(rest);
+    result
+}
+
+/// Get the replacement for a template variable
+fn replacement(name: &str) -> &'static str {
+    INTERVAL_VARIABLES
+        .iter()
+        .find(|(variable, _)| *variable == name)
+        .map_or(VARIABLE_PLACEHOLDER, |(_, replacement)| replacement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn only(labels: &[&str]) -> LabelUsage {
+        LabelUsage::Only(labels.iter().map(|l| l.to_string()).collect())
+    }
+
+    fn all_except(labels: &[&str]) -> LabelUsage {
+        LabelUsage::AllExcept(labels.iter().map(|l| l.to_string()).collect())
+    }
+
+    fn labels(expr: &str, metric: &str) -> Option<LabelUsage> {
+        MetricReferences::from_expr(expr).unwrap().labels(metric)
+    }
+
+    #[test]
+    fn interpolate_variable_syntaxes() {
+        assert_eq!(
+            interpolate(r#"up{job="$job"}"#),
+            r#"up{job="__template_variable__"}"#
+        );
+        assert_eq!(
+            interpolate(r#"up{job="${job}"}"#),
+            r#"up{job="__template_variable__"}"#
+        );
+        assert_eq!(
+            interpolate(r#"up{job=~"${job:regex}"}"#),
+            r#"up{job=~"__template_variable__"}"#
+        );
+        assert_eq!(
+            interpolate(r#"up{job="[[job]]"}"#),
+            r#"up{job="__template_variable__"}"#
+        );
+        assert_eq!(interpolate("a[5m]"), "a[5m]");
+        assert_eq!(interpolate("$"), "$");
+    }
+
+    #[test]
+    fn interpolate_interval_variables() {
+        assert_eq!(interpolate("rate(a[$__rate_interval])"), "rate(a[5m])");
+        assert_eq!(interpolate("rate(a[${__interval}])"), "rate(a[5m])");
+        assert_eq!(interpolate("a / [[__range_s]]"), "a / 300");
+        assert_eq!(
+            interpolate("max_over_time(a[$__range:$__interval])"),
+            "max_over_time(a[5m:5m])"
+        );
+        assert_eq!(interpolate("a / $__interval_ms"), "a / 300000");
+        assert_eq!(interpolate("a / ${__range_s}"), "a / 300");
+        assert_eq!(interpolate("a / $__range_ms"), "a / 300000");
+        assert_eq!(interpolate("a / $__rate_interval_ms"), "a / 300000");
+    }
+
+    #[test]
+    fn expressions_with_variables_parse() {
+        for expr in [
+            "rate(bar_total[$__rate_interval]) * 1000 / $__interval_ms",
+            "sum(increase(bar_total[$__range])) / $__range_s",
+            "max_over_time(bar_total[$__range:$__interval])",
+            r#"sum by ($group) (bar_total{job=~"$job"})"#,
+        ] {
+            let references =
+                MetricReferences::from_expr(expr).unwrap_or_else(|e| panic!("{}: {}", expr, e));
+
+            assert!(references.contains("bar_total"), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn names_from_variables_are_ignored() {
+        let references = MetricReferences::from_expr(r#"{__name__="$metric"}"#).unwrap();
+        assert!(references.is_empty());
+
+        let references = MetricReferences::from_expr("${metric}_total").unwrap();
+        assert!(!references.contains("__template_variable___total"));
+    }
+
+    #[test]
+    fn name_patterns() {
+        let references =
+            MetricReferences::from_expr(r#"{__name__=~"http_.*", __name__!="http_debug"}"#)
+                .unwrap();
+
+        assert!(references.contains("http_requests_total"));
+        assert!(!references.contains("http_debug"));
+        assert!(!references.contains("grpc_requests_total"));
+
+        // Regexes are anchored
+        let references = MetricReferences::from_expr(r#"{__name__=~"up"}"#).unwrap();
+        assert!(references.contains("up"));
+        assert!(!references.contains("upstream"));
+    }
+
+    #[test]
+    fn label_usage_of_aggregations() {
+        assert_eq!(labels("sum by (job) (a)", "a"), Some(only(&["job"])));
+        assert_eq!(
+            labels(r#"sum by (job) (a{code="500"})"#, "a"),
+            Some(only(&["code", "job"]))
+        );
+        assert_eq!(
+            labels("sum without (pod) (a)", "a"),
+            Some(all_except(&["pod"]))
+        );
+        assert_eq!(labels("count(a)", "a"), Some(only(&[])));
+        assert_eq!(
+            labels("sum by (job) (sum without (pod) (a))", "a"),
+            Some(only(&["job"]))
+        );
+        assert_eq!(labels("topk(5, a)", "a"), Some(LabelUsage::All));
+        assert_eq!(labels("a", "a"), Some(LabelUsage::All));
+        assert_eq!(labels("a", "b"), None);
+    }
+
+    #[test]
+    fn label_usage_of_functions() {
+        assert_eq!(
+            labels(
+                r#"sum by (service) (label_replace(a, "service", "$1", "job", "(.*)"))"#,
+                "a"
+            ),
+            Some(only(&["job", "service"]))
+        );
+        assert_eq!(
+            labels(
+                "histogram_quantile(0.9, sum by (le) (rate(a_bucket[5m])))",
+                "a_bucket"
+            ),
+            Some(only(&["le"]))
+        );
+        assert_eq!(labels("absent(a)", "a"), Some(only(&[])));
+    }
+
+    #[test]
+    fn label_usage_of_binary_operations() {
+        // The right-hand side of `and` only needs the labels it is matched on
+        assert_eq!(
+            labels("sum by (job) (a) and on(job) b", "b"),
+            Some(only(&["job"]))
+        );
+        assert_eq!(
+            labels("sum by (job) (a) and on(job) b", "a"),
+            Some(only(&["job"]))
+        );
+        assert_eq!(
+            labels("sum by (job) (a) unless b", "b"),
+            Some(LabelUsage::All)
+        );
+        assert_eq!(
+            labels("sum by (job) (a * on(instance) group_left(version) b)", "b"),
+            Some(only(&["instance", "job", "version"]))
+        );
+        assert_eq!(
+            labels("sum by (job) (a / ignoring(code) b)", "b"),
+            Some(all_except(&["code"]))
+        );
+        assert_eq!(labels("sum by (job) (a) * 2", "a"), Some(only(&["job"])));
+    }
+
+    #[test]
+    fn label_usage_is_merged_across_expressions() {
+        let mut references = MetricReferences::default();
+        references.add_expr("sum by (job) (a)").unwrap();
+        references.add_expr("sum by (code) (a)").unwrap();
+
+        assert_eq!(references.labels("a"), Some(only(&["code", "job"])));
+
+        references.add_expr("a").unwrap();
+        assert_eq!(references.labels("a"), Some(LabelUsage::All));
+        assert_eq!(
+            LabelUsage::AllExcept(BTreeSet::from(["pod".to_string()])).union(&only(&["pod"])),
+            all_except(&[])
+        );
+    }
+}
@@ -0,0 +1,103 @@
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    String(String),
+    VectorSelector(VectorSelector),
+    /// A range vector selector such as `foo[5m]`
+    MatrixSelector(VectorSelector),
+    Subquery(Box<Expr>),
+    Paren(Box<Expr>),
+    Unary(Box<Expr>),
+    Call {
+        func: String,
+        args: Vec<Expr>,
+    },
+    Aggregate {
+        op: String,
+        grouping: Option<Grouping>,
+        param: Option<Box<Expr>>,
+        expr: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+        matching: Option<VectorMatching>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VectorSelector {
+    pub name: Option<String>,
+    pub matchers: Vec<LabelMatcher>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelMatcher {
+    pub name: String,
+    pub op: MatchOp,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MatchOp {
+    Equal,
+    NotEqual,
+    Regex,
+    NotRegex,
+}
+
+/// A `by (...)` or `without (...)` clause of an aggregation
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grouping {
+    pub without: bool,
+    pub labels: Vec<String>,
+}
+
+/// The `on`/`ignoring` and `group_left`/`group_right` modifiers of a binary operation
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VectorMatching {
+    pub ignoring: bool,
+    pub labels: Vec<String>,
+    /// Extra labels copied from the "one" side in a `group_left`/`group_right` join
+    pub include: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Atan2,
+    Eql,
+    Neq,
+    Lss,
+    Lte,
+    Gtr,
+    Gte,
+    And,
+    Or,
+    Unless,
+}
+
+impl VectorSelector {
+    /// Get the matchers on the metric name, including the name given outside the braces
+    pub fn name_matchers(&self) -> Vec<LabelMatcher> {
+        let name = self.name.iter().map(|name| LabelMatcher {
+            name: "__name__".to_string(),
+            op: MatchOp::Equal,
+            value: name.clone(),
+        });
+
+        name.chain(
+            self.matchers
+                .iter()
+                .filter(|matcher| matcher.name == "__name__")
+                .cloned(),
+        )
+        .collect()
+    }
+}
@@ -0,0 +1,218 @@
+use crate::promql::ast::{BinaryOp, Expr, Grouping, VectorMatching, VectorSelector};
+use std::collections::BTreeSet;
+
+/// Aggregations returning a subset of the input series with their labels intact
+const SELECTING_AGGREGATIONS: &[&str] = &["topk", "bottomk", "limitk", "limit_ratio"];
+
+/// Functions returning a scalar, or taking no vector at all
+const SCALAR_FUNCTIONS: &[&str] = &["scalar", "time", "pi"];
+
+/// The labels of a metric that a query depends on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LabelUsage {
+    /// Every label, such as when series are returned with their labels intact
+    All,
+    /// Every label except these
+    AllExcept(BTreeSet<String>),
+    /// Only these labels
+    Only(BTreeSet<String>),
+}
+
+impl LabelUsage {
+    /// No labels at all
+    pub fn none() -> Self {
+        LabelUsage::Only(BTreeSet::new())
+    }
+
+    fn only<'a>(labels: impl IntoIterator<Item = &'a String>) -> Self {
+        LabelUsage::Only(labels.into_iter().cloned().collect())
+    }
+
+    fn all_except<'a>(labels: impl IntoIterator<Item = &'a String>) -> Self {
+        LabelUsage::AllExcept(labels.into_iter().cloned().collect())
+    }
+
+    /// Check whether a label is used
+    pub fn uses(&self, label: &str) -> bool {
+        match self {
+            LabelUsage::All => true,
+            LabelUsage::AllExcept(excluded) => !excluded.contains(label),
+            LabelUsage::Only(labels) => labels.contains(label),
+        }
+    }
+
+    /// The labels used by either of two usages
+    pub fn union(&self, other: &Self) -> Self {
+        use LabelUsage::*;
+
+        match (self, other) {
+            (All, _) | (_, All) => All,
+            (AllExcept(a), AllExcept(b)) => AllExcept(a.intersection(b).cloned().collect()),
+            (AllExcept(excluded), Only(labels)) | (Only(labels), AllExcept(excluded)) => {
+                AllExcept(excluded.difference(labels).cloned().collect())
+            }
+            (Only(a), Only(b)) => Only(a.union(b).cloned().collect()),
+        }
+    }
+
+    /// The labels used by both of two usages
+    pub fn intersection(&self, other: &Self) -> Self {
+        use LabelUsage::*;
+
+        match (self, other) {
+            (All, usage) | (usage, All) => usage.clone(),
+            (AllExcept(a), AllExcept(b)) => AllExcept(a.union(b).cloned().collect()),
+            (AllExcept(excluded), Only(labels)) | (Only(labels), AllExcept(excluded)) => {
+                Only(labels.difference(excluded).cloned().collect())
+            }
+            (Only(a), Only(b)) => Only(a.intersection(b).cloned().collect()),
+        }
+    }
+}
+
+/// Get the labels each selector in an expression depends on.
+///
+/// The result of the expression is assumed to be used with all of its labels, as it is when graphed
+/// or recorded. Labels are then narrowed down through aggregations and widened by label matchers,
+/// vector matching and functions reading labels.
+pub fn selector_labels(expr: &Expr) -> Vec<(&VectorSelector, LabelUsage)> {
+    let mut selectors = Vec::new();
+    visit(expr, LabelUsage::All, &mut selectors);
+
+    selectors
+}
+
+/// Visit an expression whose result is used with the given labels
+fn visit<'a>(
+    expr: &'a Expr,
+    used: LabelUsage,
+    selectors: &mut Vec<(&'a VectorSelector, LabelUsage)>,
+) {
+    match expr {
+        Expr::Number(_) | Expr::String(_) => {}
+        Expr::VectorSelector(selector) | Expr::MatrixSelector(selector) => {
+            let matchers = selector
+                .matchers
+                .iter()
+                .filter(|m| m.name != "__name__")
+                .map(|m| &m.name);
+
+            selectors.push((selector, used.union(&LabelUsage::only(matchers))));
+        }
+        Expr::Subquery(inner) | Expr::Paren(inner) | Expr::Unary(inner) => {
+            visit(inner, used, selectors)
+        }
+        Expr::Call { func, args } => visit_call(func, args, used, selectors),
+        Expr::Aggregate {
+            op,
+            grouping,
+            param,
+            expr,
+        } => {
+            if let Some(param) = param {
+                visit(param, LabelUsage::none(), selectors);
+            }
+
+            let inner = match SELECTING_AGGREGATIONS.contains(&op.as_str()) {
+                true => used.union(&grouping_usage(grouping.as_ref())),
+                false => used.intersection(&grouping_usage(grouping.as_ref())),
+            };
+
+            visit(expr, inner, selectors);
+        }
+        Expr::Binary {
+            op,
+            lhs,
+            rhs,
+            matching,
+        } => visit_binary(*op, lhs, rhs, matching.as_ref(), used, selectors),
+    }
+}
+
+fn visit_call<'a>(
+    func: &str,
+    args: &'a [Expr],
+    used: LabelUsage,
+    selectors: &mut Vec<(&'a VectorSelector, LabelUsage)>,
+) {
+    let strings: Vec<String> = args
+        .iter()
+        .filter_map(|arg| match arg {
+            Expr::String(value) => Some(value.clone()),
+            _ => None,
+        })
+        .collect();
+
+    let inner = match func {
+        // Only the presence of series matters, or the result has no labels
+        "absent" | "absent_over_time" | "scalar" | "vector" => LabelUsage::none(),
+        // Bucket boundaries are read from the `le` label
+        "histogram_quantile" | "histogram_fraction" => {
+            used.union(&LabelUsage::Only(BTreeSet::from(["le".to_string()])))
+        }
+        // label_replace(v, dst, replacement, src, regex)
+        "label_replace" => used.union(&LabelUsage::only(strings.get(2))),
+        // label_join(v, dst, separator, src...)
+        "label_join" => used.union(&LabelUsage::only(strings.iter().skip(2))),
+        "sort_by_label" | "sort_by_label_desc" => used.union(&LabelUsage::only(&strings)),
+        _ => used,
+    };
+
+    for arg in args {
+        visit(arg, inner.clone(), selectors);
+    }
+}
+
+fn visit_binary<'a>(
+    op: BinaryOp,
+    lhs: &'a Expr,
+    rhs: &'a Expr,
+    matching: Option<&VectorMatching>,
+    used: LabelUsage,
+    selectors: &mut Vec<(&'a VectorSelector, LabelUsage)>,
+) {
+    // Operations with a scalar apply to each series as is
+    if is_scalar(lhs) || is_scalar(rhs) {
+        visit(lhs, used.clone(), selectors);
+        visit(rhs, used, selectors);
+        return;
+    }
+
+    // Series are matched on every label without `on(...)`
+    let matched = match matching {
+        Some(matching) if !matching.ignoring => {
+            LabelUsage::only(&matching.labels).union(&LabelUsage::only(&matching.include))
+        }
+        Some(matching) => LabelUsage::all_except(&matching.labels),
+        None => LabelUsage::All,
+    };
+
+    // The right-hand side of `and` and `unless` only filters the left-hand side
+    let rhs_used = match op {
+        BinaryOp::And | BinaryOp::Unless => matched.clone(),
+        _ => used.union(&matched),
+    };
+
+    visit(lhs, used.union(&matched), selectors);
+    visit(rhs, rhs_used, selectors);
+}
+
+/// The labels kept by an aggregation's grouping clause
+fn grouping_usage(grouping: Option<&Grouping>) -> LabelUsage {
+    match grouping {
+        Some(grouping) if grouping.without => LabelUsage::all_except(&grouping.labels),
+        Some(grouping) => LabelUsage::only(&grouping.labels),
+        None => LabelUsage::none(),
+    }
+}
+
+/// Check whether an expression evaluates to a scalar
+fn is_scalar(expr: &Expr) -> bool {
+    match expr {
+        Expr::Number(_) => true,
+        Expr::Paren(inner) | Expr::Unary(inner) => is_scalar(inner),
+        Expr::Call { func, .. } => SCALAR_FUNCTIONS.contains(&func.as_str()),
+        Expr::Binary { lhs, rhs, .. } => is_scalar(lhs) && is_scalar(rhs),
+        _ => false,
+    }
+}
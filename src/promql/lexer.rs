@@ -0,0 +1,263 @@
+use anyhow::{Result, anyhow};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Identifier(String),
+    Number(f64),
+    /// A duration such as `5m` or `1h30m`, kept as written
+    Duration(String),
+    String(String),
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    Comma,
+    Colon,
+    At,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Eql,
+    Neq,
+    Lss,
+    Lte,
+    Gtr,
+    Gte,
+    Assign,
+    RegexMatch,
+    RegexNoMatch,
+}
+
+/// Split a PromQL expression into tokens
+pub fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        let (token, len) = match c {
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            // Comments run until the end of the line
+            '#' => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            '(' => (Token::LeftParen, 1),
+            ')' => (Token::RightParen, 1),
+            '{' => (Token::LeftBrace, 1),
+            '}' => (Token::RightBrace, 1),
+            '[' => (Token::LeftBracket, 1),
+            ']' => (Token::RightBracket, 1),
+            ',' => (Token::Comma, 1),
+            '@' => (Token::At, 1),
+            '+' => (Token::Add, 1),
+            '-' => (Token::Sub, 1),
+            '*' => (Token::Mul, 1),
+            '/' => (Token::Div, 1),
+            '%' => (Token::Mod, 1),
+            '^' => (Token::Pow, 1),
+            '=' if next == Some('=') => (Token::Eql, 2),
+            '=' if next == Some('~') => (Token::RegexMatch, 2),
+            '=' => (Token::Assign, 1),
+            '!' if next == Some('=') => (Token::Neq, 2),
+            '!' if next == Some('~') => (Token::RegexNoMatch, 2),
+            '<' if next == Some('=') => (Token::Lte, 2),
+            '<' => (Token::Lss, 1),
+            '>' if next == Some('=') => (Token::Gte, 2),
+            '>' => (Token::Gtr, 1),
+            '"' | '\'' | '`' => {
+                let (value, len) = lex_string(&chars[i..])?;
+                (Token::String(value), len)
+            }
+            c if c.is_ascii_digit() || (c == '.' && next.is_some_and(|n| n.is_ascii_digit())) => {
+                lex_number(&chars[i..])
+            }
+            // A colon only starts an identifier when followed by a name, as in `:rate5m`.
+            // Otherwise it separates the range and resolution of a subquery.
+            ':' if !next.is_some_and(|n| n.is_ascii_alphabetic() || n == '_') => (Token::Colon, 1),
+            c if is_identifier_start(c) => {
+                let len = chars[i..]
+                    .iter()
+                    .take_while(|c| is_identifier_char(**c))
+                    .count();
+
+                (Token::Identifier(chars[i..i + len].iter().collect()), len)
+            }
+            c => return Err(anyhow!("unexpected character '{}' at position {}", c, i)),
+        };
+
+        tokens.push(token);
+        i += len;
+    }
+
+    Ok(tokens)
+}
+
+/// Lex a quoted string, returning its unescaped value and length in characters
+fn lex_string(chars: &[char]) -> Result<(String, usize)> {
+    let quote = chars[0];
+    let mut value = String::new();
+    let mut i = 1;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c == quote {
+            return Ok((value, i + 1));
+        }
+
+        // Raw strings don't support escapes
+        if c == '\\' && quote != '`' {
+            i += 1;
+
+            match chars.get(i) {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some('r') => value.push('\r'),
+                Some(c) if *c == quote || *c == '\\' => value.push(*c),
+                // Keep unknown escapes as written, they are meaningful in regexes
+                Some(c) => {
+                    value.push('\\');
+                    value.push(*c);
+                }
+                None => break,
+            }
+        } else {
+            value.push(c);
+        }
+
+        i += 1;
+    }
+
+    Err(anyhow!("unterminated string"))
+}
+
+/// Lex a number or a duration
+fn lex_number(chars: &[char]) -> (Token, usize) {
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        // Include the sign of an exponent, as in `1e-3`
+        let signed_exponent = (c == '-' || c == '+')
+            && i > 0
+            && matches!(chars[i - 1], 'e' | 'E')
+            && chars[..i]
+                .iter()
+                .all(|c| c.is_ascii_digit() || *c == '.' || *c == 'e' || *c == 'E');
+
+        if c.is_ascii_alphanumeric() || c == '.' || c == '_' || signed_exponent {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+
+    let text: String = chars[..i].iter().collect();
+
+    let number = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok().map(|n| n as f64),
+        None => text.replace('_', "").parse::<f64>().ok(),
+    };
+
+    match number {
+        Some(number) => (Token::Number(number), i),
+        None => (Token::Duration(text), i),
+    }
+}
+
+pub fn is_identifier_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == ':'
+}
+
+pub fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == ':'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(input: &str) -> String {
+        match tokenize(input).unwrap().as_slice() {
+            [Token::String(value)] => value.clone(),
+            tokens => panic!("expected a string, got {:?}", tokens),
+        }
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(string(r#""a\"b""#), "a\"b");
+        assert_eq!(string(r"'a\'b'"), "a'b");
+        assert_eq!(string(r#""a\\b""#), "a\\b");
+        assert_eq!(string(r#""a\nb\tc""#), "a\nb\tc");
+        // Unknown escapes are kept for regexes
+        assert_eq!(string(r#""a\.b""#), "a\\.b");
+        // Raw strings don't support escapes
+        assert_eq!(string(r"`a\nb`"), "a\\nb");
+        assert!(tokenize(r#""a\""#).is_err());
+    }
+
+    #[test]
+    fn durations() {
+        for duration in ["5m", "1h30m", "1d", "500ms", "1y2w"] {
+            assert_eq!(
+                tokenize(duration).unwrap(),
+                [Token::Duration(duration.to_string())]
+            );
+        }
+
+        assert_eq!(
+            tokenize("a[5m:1m]").unwrap(),
+            [
+                Token::Identifier("a".to_string()),
+                Token::LeftBracket,
+                Token::Duration("5m".to_string()),
+                Token::Colon,
+                Token::Duration("1m".to_string()),
+                Token::RightBracket,
+            ]
+        );
+    }
+
+    #[test]
+    fn number_formats() {
+        for (input, number) in [
+            ("42", 42.0),
+            ("1.5", 1.5),
+            (".5", 0.5),
+            ("1e3", 1000.0),
+            ("1E-3", 0.001),
+            ("2.5e+2", 250.0),
+            ("0x1F", 31.0),
+            ("1_000", 1000.0),
+        ] {
+            assert_eq!(
+                tokenize(input).unwrap(),
+                [Token::Number(number)],
+                "{}",
+                input
+            );
+        }
+
+        // Inf and NaN are left to the parser
+        assert_eq!(
+            tokenize("Inf").unwrap(),
+            [Token::Identifier("Inf".to_string())]
+        );
+    }
+}
@@ -0,0 +1,714 @@
+use crate::promql::{
+    ast::{BinaryOp, Expr, Grouping, LabelMatcher, MatchOp, VectorMatching, VectorSelector},
+    lexer::{Token, tokenize},
+};
+use anyhow::{Result, anyhow, bail};
+
+/// Aggregation operators
+const AGGREGATIONS: &[&str] = &[
+    "sum",
+    "min",
+    "max",
+    "avg",
+    "group",
+    "stddev",
+    "stdvar",
+    "count",
+    "count_values",
+    "bottomk",
+    "topk",
+    "quantile",
+    "limitk",
+    "limit_ratio",
+];
+
+/// Parse a PromQL expression
+pub fn parse(input: &str) -> Result<Expr> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser { tokens, pos: 0 };
+
+    let expr = parser.parse_expr(0)?;
+
+    if let Some(token) = parser.peek() {
+        bail!("unexpected token {:?} after expression", token);
+    }
+
+    Ok(expr)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(anyhow!("expected {:?}, found {:?}", expected, token)),
+            None => Err(anyhow!("expected {:?}, found end of input", expected)),
+        }
+    }
+
+    /// Check whether the next token is the given keyword
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Identifier(ident)) if ident.eq_ignore_ascii_case(keyword))
+    }
+
+    /// Consume the next token if it is the given keyword
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.peek_keyword(keyword);
+
+        if found {
+            self.pos += 1;
+        }
+
+        found
+    }
+
+    /// Parse a binary expression whose operators bind at least as tightly as `min_precedence`
+    fn parse_expr(&mut self, min_precedence: u8) -> Result<Expr> {
+        let mut lhs = self.parse_unary()?;
+
+        while let Some(op) = self.peek_binary_op() {
+            let precedence = precedence(op);
+
+            if precedence < min_precedence {
+                break;
+            }
+
+            self.pos += 1;
+
+            // Comparisons may be followed by `bool`
+            self.eat_keyword("bool");
+
+            let matching = self.parse_vector_matching()?;
+
+            // `^` is right-associative, everything else is left-associative
+            let next_precedence = match op {
+                BinaryOp::Pow => precedence,
+                _ => precedence + 1,
+            };
+
+            let rhs = self.parse_expr(next_precedence)?;
+
+            lhs = Expr::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+                matching,
+            };
+        }
+
+        Ok(lhs)
+    }
+
+    fn peek_binary_op(&self) -> Option<BinaryOp> {
+        let op = match self.peek()? {
+            Token::Add => BinaryOp::Add,
+            Token::Sub => BinaryOp::Sub,
+            Token::Mul => BinaryOp::Mul,
+            Token::Div => BinaryOp::Div,
+            Token::Mod => BinaryOp::Mod,
+            Token::Pow => BinaryOp::Pow,
+            Token::Eql => BinaryOp::Eql,
+            Token::Neq => BinaryOp::Neq,
+            Token::Lss => BinaryOp::Lss,
+            Token::Lte => BinaryOp::Lte,
+            Token::Gtr => BinaryOp::Gtr,
+            Token::Gte => BinaryOp::Gte,
+            Token::Identifier(ident) => match ident.to_lowercase().as_str() {
+                "and" => BinaryOp::And,
+                "or" => BinaryOp::Or,
+                "unless" => BinaryOp::Unless,
+                "atan2" => BinaryOp::Atan2,
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        Some(op)
+    }
+
+    /// Parse the optional `on`/`ignoring` and `group_left`/`group_right` modifiers
+    fn parse_vector_matching(&mut self) -> Result<Option<VectorMatching>> {
+        let ignoring = if self.eat_keyword("on") {
+            false
+        } else if self.eat_keyword("ignoring") {
+            true
+        } else {
+            return Ok(None);
+        };
+
+        let labels = self.parse_label_list()?;
+        let mut include = Vec::new();
+
+        if self.eat_keyword("group_left") || self.eat_keyword("group_right") {
+            // The list of included labels is optional
+            if self.peek() == Some(&Token::LeftParen) {
+                include = self.parse_label_list()?;
+            }
+        }
+
+        Ok(Some(VectorMatching {
+            ignoring,
+            labels,
+            include,
+        }))
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        match self.peek() {
+            Some(Token::Sub) | Some(Token::Add) => {
+                self.pos += 1;
+
+                // Unary operators bind tighter than everything but `^`
+                let expr = self.parse_expr(precedence(BinaryOp::Pow))?;
+
+                Ok(Expr::Unary(Box::new(expr)))
+            }
+            _ => {
+                let expr = self.parse_primary()?;
+                self.parse_postfix(expr)
+            }
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(Expr::Number(number)),
+            Some(Token::String(value)) => Ok(Expr::String(value)),
+            Some(Token::LeftParen) => {
+                let expr = self.parse_expr(0)?;
+                self.expect(Token::RightParen)?;
+
+                Ok(Expr::Paren(Box::new(expr)))
+            }
+            Some(Token::LeftBrace) => {
+                let selector = self.parse_selector(None)?;
+                Ok(Expr::VectorSelector(selector))
+            }
+            Some(Token::Identifier(ident)) => self.parse_identifier(ident),
+            Some(token) => Err(anyhow!("unexpected token {:?}", token)),
+            None => Err(anyhow!("unexpected end of input")),
+        }
+    }
+
+    /// Parse an expression starting with an identifier: an aggregation, function call, or selector
+    fn parse_identifier(&mut self, ident: String) -> Result<Expr> {
+        let lower = ident.to_lowercase();
+
+        if AGGREGATIONS.contains(&lower.as_str())
+            && (self.peek() == Some(&Token::LeftParen)
+                || self.peek_keyword("by")
+                || self.peek_keyword("without"))
+        {
+            return self.parse_aggregation(lower);
+        }
+
+        if self.peek() == Some(&Token::LeftParen) {
+            self.pos += 1;
+            let args = self.parse_args()?;
+
+            return Ok(Expr::Call { func: ident, args });
+        }
+
+        match lower.as_str() {
+            "inf" => return Ok(Expr::Number(f64::INFINITY)),
+            "nan" => return Ok(Expr::Number(f64::NAN)),
+            _ => {}
+        }
+
+        if self.peek() == Some(&Token::LeftBrace) {
+            self.pos += 1;
+            let selector = self.parse_selector(Some(ident))?;
+
+            return Ok(Expr::VectorSelector(selector));
+        }
+
+        Ok(Expr::VectorSelector(VectorSelector {
+            name: Some(ident),
+            matchers: Vec::new(),
+        }))
+    }
+
+    fn parse_aggregation(&mut self, op: String) -> Result<Expr> {
+        // The grouping clause may come before or after the arguments
+        let mut grouping = self.parse_grouping()?;
+
+        self.expect(Token::LeftParen)?;
+        let mut args = self.parse_args()?;
+
+        if grouping.is_none() {
+            grouping = self.parse_grouping()?;
+        }
+
+        let expr = args
+            .pop()
+            .ok_or_else(|| anyhow!("aggregation '{}' has no arguments", op))?;
+
+        let param = args.pop().map(Box::new);
+
+        Ok(Expr::Aggregate {
+            op,
+            grouping,
+            param,
+            expr: Box::new(expr),
+        })
+    }
+
+    fn parse_grouping(&mut self) -> Result<Option<Grouping>> {
+        let without = if self.eat_keyword("by") {
+            false
+        } else if self.eat_keyword("without") {
+            true
+        } else {
+            return Ok(None);
+        };
+
+        let labels = self.parse_label_list()?;
+
+        Ok(Some(Grouping { without, labels }))
+    }
+
+    /// Parse a parenthesized, comma-separated list of label names
+    fn parse_label_list(&mut self) -> Result<Vec<String>> {
+        self.expect(Token::LeftParen)?;
+        let mut labels = Vec::new();
+
+        loop {
+            match self.next() {
+                Some(Token::RightParen) => break,
+                Some(Token::Identifier(label)) | Some(Token::String(label)) => labels.push(label),
+                Some(token) => bail!("unexpected token {:?} in label list", token),
+                None => bail!("unterminated label list"),
+            }
+
+            match self.next() {
+                Some(Token::Comma) => continue,
+                Some(Token::RightParen) => break,
+                Some(token) => bail!("unexpected token {:?} in label list", token),
+                None => bail!("unterminated label list"),
+            }
+        }
+
+        Ok(labels)
+    }
+
+    /// Parse function arguments, after the opening parenthesis
+    fn parse_args(&mut self) -> Result<Vec<Expr>> {
+        let mut args = Vec::new();
+
+        if self.peek() == Some(&Token::RightParen) {
+            self.pos += 1;
+            return Ok(args);
+        }
+
+        loop {
+            args.push(self.parse_expr(0)?);
+
+            match self.next() {
+                Some(Token::Comma) => continue,
+                Some(Token::RightParen) => break,
+                Some(token) => bail!("unexpected token {:?} in arguments", token),
+                None => bail!("unterminated arguments"),
+            }
+        }
+
+        Ok(args)
+    }
+
+    /// Parse label matchers, after the opening brace
+    fn parse_selector(&mut self, name: Option<String>) -> Result<VectorSelector> {
+        let mut selector = VectorSelector {
+            name,
+            matchers: Vec::new(),
+        };
+
+        loop {
+            let label = match self.next() {
+                Some(Token::RightBrace) => break,
+                Some(Token::Identifier(label)) => label,
+                Some(Token::String(label)) => {
+                    // A quoted name on its own is the metric name, as in `{"my.metric"}`
+                    if matches!(self.peek(), Some(Token::Comma) | Some(Token::RightBrace)) {
+                        selector.name = Some(label);
+                        self.eat_separator()?;
+                        continue;
+                    }
+
+                    label
+                }
+                Some(token) => bail!("unexpected token {:?} in label matchers", token),
+                None => bail!("unterminated label matchers"),
+            };
+
+            let op = match self.next() {
+                Some(Token::Assign) => MatchOp::Equal,
+                Some(Token::Neq) => MatchOp::NotEqual,
+                Some(Token::RegexMatch) => MatchOp::Regex,
+                Some(Token::RegexNoMatch) => MatchOp::NotRegex,
+                Some(token) => bail!("unexpected token {:?} in label matcher", token),
+                None => bail!("unterminated label matchers"),
+            };
+
+            let value = match self.next() {
+                Some(Token::String(value)) => value,
+                Some(token) => bail!("expected string in label matcher, found {:?}", token),
+                None => bail!("unterminated label matchers"),
+            };
+
+            selector.matchers.push(LabelMatcher {
+                name: label,
+                op,
+                value,
+            });
+
+            if self.eat_separator()? {
+                break;
+            }
+        }
+
+        Ok(selector)
+    }
+
+    /// Consume a comma or closing brace after a label matcher, returning true for the latter
+    fn eat_separator(&mut self) -> Result<bool> {
+        match self.next() {
+            Some(Token::Comma) => {
+                // Trailing commas are allowed
+                if self.peek() == Some(&Token::RightBrace) {
+                    self.pos += 1;
+                    return Ok(true);
+                }
+
+                Ok(false)
+            }
+            Some(Token::RightBrace) => Ok(true),
+            Some(token) => bail!("unexpected token {:?} in label matchers", token),
+            None => bail!("unterminated label matchers"),
+        }
+    }
+
+    /// Parse range selectors, subqueries, and the `offset` and `@` modifiers
+    fn parse_postfix(&mut self, mut expr: Expr) -> Result<Expr> {
+        loop {
+            match self.peek() {
+                Some(Token::LeftBracket) => {
+                    self.pos += 1;
+                    let subquery = self.skip_range()?;
+
+                    expr = match (expr, subquery) {
+                        (Expr::VectorSelector(selector), false) => Expr::MatrixSelector(selector),
+                        (expr, _) => Expr::Subquery(Box::new(expr)),
+                    };
+                }
+                Some(Token::Identifier(ident)) if ident.eq_ignore_ascii_case("offset") => {
+                    self.pos += 1;
+
+                    if self.peek() == Some(&Token::Sub) {
+                        self.pos += 1;
+                    }
+
+                    self.skip_duration()?;
+                }
+                Some(Token::At) => {
+                    self.pos += 1;
+
+                    match self.next() {
+                        Some(Token::Number(_)) => {}
+                        Some(Token::Identifier(_)) => {
+                            // `start()` and `end()`
+                            self.expect(Token::LeftParen)?;
+                            self.expect(Token::RightParen)?;
+                        }
+                        Some(token) => bail!("unexpected token {:?} after '@'", token),
+                        None => bail!("unexpected end of input after '@'"),
+                    }
+                }
+                _ => return Ok(expr),
+            }
+        }
+    }
+
+    /// Skip the contents of a range, after the opening bracket.
+    ///
+    /// Returns whether the range belongs to a subquery. Durations are accepted leniently, since
+    /// they are often template variables.
+    fn skip_range(&mut self) -> Result<bool> {
+        let mut subquery = false;
+
+        loop {
+            match self.next() {
+                Some(Token::RightBracket) => return Ok(subquery),
+                Some(Token::Colon) => subquery = true,
+                Some(_) => {}
+                None => bail!("unterminated range"),
+            }
+        }
+    }
+
+    fn skip_duration(&mut self) -> Result<()> {
+        match self.next() {
+            Some(Token::Duration(_)) | Some(Token::Number(_)) | Some(Token::Identifier(_)) => {
+                Ok(())
+            }
+            Some(token) => Err(anyhow!("expected duration, found {:?}", token)),
+            None => Err(anyhow!("expected duration, found end of input")),
+        }
+    }
+}
+
+/// Binding power of a binary operator
+fn precedence(op: BinaryOp) -> u8 {
+    match op {
+        BinaryOp::Or => 1,
+        BinaryOp::And | BinaryOp::Unless => 2,
+        BinaryOp::Eql
+        | BinaryOp::Neq
+        | BinaryOp::Lss
+        | BinaryOp::Lte
+        | BinaryOp::Gtr
+        | BinaryOp::Gte => 3,
+        BinaryOp::Add | BinaryOp::Sub => 4,
+        BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod | BinaryOp::Atan2 => 5,
+        BinaryOp::Pow => 6,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::promql::labels::selector_labels;
+
+    fn selector(name: &str) -> Expr {
+        Expr::VectorSelector(VectorSelector {
+            name: Some(name.to_string()),
+            matchers: Vec::new(),
+        })
+    }
+
+    fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+        Expr::Binary {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+            matching: None,
+        }
+    }
+
+    #[test]
+    fn precedence_of_binary_operators() {
+        assert_eq!(
+            parse("a + b * c").unwrap(),
+            binary(
+                BinaryOp::Add,
+                selector("a"),
+                binary(BinaryOp::Mul, selector("b"), selector("c")),
+            ),
+        );
+
+        assert_eq!(
+            parse("a - b - c").unwrap(),
+            binary(
+                BinaryOp::Sub,
+                binary(BinaryOp::Sub, selector("a"), selector("b")),
+                selector("c"),
+            ),
+        );
+
+        assert_eq!(
+            parse("a ^ b ^ c").unwrap(),
+            binary(
+                BinaryOp::Pow,
+                selector("a"),
+                binary(BinaryOp::Pow, selector("b"), selector("c")),
+            ),
+        );
+
+        assert_eq!(
+            parse("a > bool 1 or b and c").unwrap(),
+            binary(
+                BinaryOp::Or,
+                binary(BinaryOp::Gtr, selector("a"), Expr::Number(1.0)),
+                binary(BinaryOp::And, selector("b"), selector("c")),
+            ),
+        );
+    }
+
+    #[test]
+    fn unary_operators() {
+        assert_eq!(
+            parse("-a * b").unwrap(),
+            binary(
+                BinaryOp::Mul,
+                Expr::Unary(Box::new(selector("a"))),
+                selector("b"),
+            ),
+        );
+
+        // `^` binds tighter than unary minus
+        assert_eq!(
+            parse("-a ^ 2").unwrap(),
+            Expr::Unary(Box::new(binary(
+                BinaryOp::Pow,
+                selector("a"),
+                Expr::Number(2.0),
+            ))),
+        );
+
+        assert_eq!(
+            parse("a - -1").unwrap(),
+            binary(
+                BinaryOp::Sub,
+                selector("a"),
+                Expr::Unary(Box::new(Expr::Number(1.0))),
+            ),
+        );
+    }
+
+    #[test]
+    fn range_selectors_and_subqueries() {
+        assert!(matches!(parse("a[5m]").unwrap(), Expr::MatrixSelector(_)));
+        assert!(matches!(parse("a[1h:5m]").unwrap(), Expr::Subquery(_)));
+        assert!(matches!(parse("a[1h:]").unwrap(), Expr::Subquery(_)));
+
+        let Expr::Call { func, args } = parse("max_over_time(rate(a[5m])[1h:1m])").unwrap() else {
+            panic!("expected a function call");
+        };
+
+        assert_eq!(func, "max_over_time");
+        assert!(matches!(&args[0], Expr::Subquery(inner) if matches!(**inner, Expr::Call { .. })));
+    }
+
+    #[test]
+    fn offset_and_at_modifiers() {
+        for expr in [
+            "a offset 5m",
+            "a offset -1h",
+            "a[5m] offset 1d",
+            "a @ 1609746000",
+            "a @ start()",
+            "a[5m] @ end() offset 5m",
+            "rate(a[5m] offset 1w)",
+        ] {
+            let parsed = parse(expr).unwrap_or_else(|e| panic!("{}: {}", expr, e));
+            assert_eq!(selector_labels(&parsed).len(), 1, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn vector_matching() {
+        let Expr::Binary { matching, .. } =
+            parse("a * on(job, instance) group_left(version) b").unwrap()
+        else {
+            panic!("expected a binary expression");
+        };
+
+        assert_eq!(
+            matching,
+            Some(VectorMatching {
+                ignoring: false,
+                labels: vec!["job".to_string(), "instance".to_string()],
+                include: vec!["version".to_string()],
+            }),
+        );
+
+        let Expr::Binary { matching, .. } = parse("a / ignoring(code) group_right b").unwrap()
+        else {
+            panic!("expected a binary expression");
+        };
+
+        assert_eq!(
+            matching,
+            Some(VectorMatching {
+                ignoring: true,
+                labels: vec!["code".to_string()],
+                include: Vec::new(),
+            }),
+        );
+    }
+
+    #[test]
+    fn name_matchers() {
+        let Expr::VectorSelector(selector) =
+            parse(r#"{__name__=~"http_.*", job!="api", "quoted.label"="x",}"#).unwrap()
+        else {
+            panic!("expected a vector selector");
+        };
+
+        assert_eq!(selector.name, None);
+        assert_eq!(
+            selector.name_matchers(),
+            vec![LabelMatcher {
+                name: "__name__".to_string(),
+                op: MatchOp::Regex,
+                value: "http_.*".to_string(),
+            }],
+        );
+        assert_eq!(selector.matchers.len(), 3);
+
+        let Expr::VectorSelector(selector) = parse(r#"{"my.metric", job="api"}"#).unwrap() else {
+            panic!("expected a vector selector");
+        };
+
+        assert_eq!(selector.name.as_deref(), Some("my.metric"));
+    }
+
+    #[test]
+    fn aggregations() {
+        let Expr::Aggregate {
+            op,
+            grouping,
+            param,
+            ..
+        } = parse("topk by (job) (5, a)").unwrap()
+        else {
+            panic!("expected an aggregation");
+        };
+
+        assert_eq!(op, "topk");
+        assert_eq!(
+            grouping,
+            Some(Grouping {
+                without: false,
+                labels: vec!["job".to_string()],
+            }),
+        );
+        assert_eq!(param.as_deref(), Some(&Expr::Number(5.0)));
+
+        let Expr::Aggregate { grouping, .. } = parse("sum(a) without (pod)").unwrap() else {
+            panic!("expected an aggregation");
+        };
+
+        assert!(grouping.is_some_and(|g| g.without && g.labels == ["pod"]));
+
+        // An aggregation name used as a metric name
+        assert_eq!(parse("count").unwrap(), selector("count"));
+    }
+
+    #[test]
+    fn recording_rule_names_and_comments() {
+        assert_eq!(
+            parse("job:http_requests:rate5m # comment").unwrap(),
+            selector("job:http_requests:rate5m"),
+        );
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for expr in ["sum(", "a +", "a{job=}", "a[5m", "rate(a[5m]) b", "a @"] {
+            assert!(parse(expr).is_err(), "{}", expr);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{config::RateLimits, metrics::external::Target};
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::Mutex, time::Instant};
+
+/// Spaces out requests to a single target so they don't exceed a rate
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    /// The earliest time the next request may be sent
+    next: Mutex<Instant>,
+}
+
+/// Rate limiters for each external target, shared between the clients of a target
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiters {
+    store_gateway: Option<Arc<RateLimiter>>,
+    querier: Option<Arc<RateLimiter>>,
+    ruler: Option<Arc<RateLimiter>>,
+    grafana: Option<Arc<RateLimiter>>,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter allowing `per_second` requests per second, which must be positive
+    pub fn new(per_second: f64) -> anyhow::Result<Self> {
+        if !per_second.is_finite() || per_second <= 0.0 {
+            anyhow::bail!("must be a positive number, got {}", per_second);
+        }
+
+        let interval = Duration::try_from_secs_f64(1.0 / per_second)
+            .map_err(|_| anyhow::anyhow!("{} requests per second is too low", per_second))?;
+
+        Ok(Self {
+            interval,
+            next: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Wait until a request may be sent
+    pub async fn acquire(&self) {
+        let mut next = self.next.lock().await;
+        let at = (*next).max(Instant::now());
+        *next = at + self.interval;
+        drop(next);
+
+        tokio::time::sleep_until(at).await;
+    }
+}
+
+impl RateLimiters {
+    /// Create rate limiters from the configured limits. Targets without a limit are unlimited.
+    pub fn new(limits: &RateLimits) -> anyhow::Result<Self> {
+        let limiter = |limit: Option<f64>| -> anyhow::Result<_> {
+            limit
+                .map(|limit| Ok(Arc::new(RateLimiter::new(limit)?)))
+                .transpose()
+        };
+
+        Ok(Self {
+            store_gateway: limiter(limits.store_gateway)?,
+            querier: limiter(limits.querier)?,
+            ruler: limiter(limits.ruler)?,
+            grafana: limiter(limits.grafana)?,
+        })
+    }
+
+    /// Create rate limiters for another Mimir cluster, with their own limits for the cluster's
+    /// targets but sharing the Grafana limiter
+    pub fn for_cluster(&self, limits: &RateLimits) -> anyhow::Result<Self> {
+        Ok(Self {
+            grafana: self.grafana.clone(),
+            ..Self::new(limits)?
+        })
+    }
+
+    /// Wait until a request to the target may be sent
+    pub async fn acquire(&self, target: Target) {
+        let limiter = match target {
+            Target::StoreGateway => &self.store_gateway,
+            Target::Querier => &self.querier,
+            Target::Ruler => &self.ruler,
+            Target::Grafana => &self.grafana,
+        };
+
+        if let Some(limiter) = limiter {
+            limiter.acquire().await;
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use crate::{
+    results::{MetricStatus, ResultStore},
+    usage::Usage,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+/// An output format for analysis results
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// A JSON array of metrics
+    Json,
+    /// Comma-separated values with a header row
+    Csv,
+    /// An aligned table for reading in a terminal
+    #[default]
+    Table,
+}
+
+/// A metric's result in a tenant, as reported
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Row {
+    #[serde(default)]
+    pub cluster: String,
+    pub tenant: String,
+    pub metric: String,
+    pub status: MetricStatus,
+    pub series_count: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub used_via: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depth: Option<usize>,
+}
+
+/// Tenant summary as returned by `/api/v1/tenants`
+#[derive(Deserialize, Debug)]
+struct Tenant {
+    #[serde(default)]
+    cluster: Option<String>,
+    tenant: String,
+}
+
+/// Collect the rows of every tenant's latest result
+pub fn rows(store: &ResultStore) -> Vec<Row> {
+    let mut rows = Vec::new();
+
+    store.visit(|view| {
+        for (metric, result) in &view.result.metrics {
+            let (used_via, depth) = match &result.usage {
+                Some(Usage::Direct) => (None, Some(0)),
+                Some(Usage::Via { record, depth }) => (Some(record.clone()), Some(*depth)),
+                None => (None, None),
+            };
+
+            rows.push(Row {
+                cluster: view.tenant.cluster.clone(),
+                tenant: view.tenant.id.clone(),
+                metric: metric.clone(),
+                status: result.status(),
+                series_count: result.series_count,
+                used_via,
+                depth,
+            });
+        }
+    });
+
+    rows
+}
+
+/// Fetch the rows of every tenant from the API of a running analyzer
+pub async fn fetch(url: &str) -> anyhow::Result<Vec<Row>> {
+    let client = reqwest::Client::new();
+    let base = reqwest::Url::parse(url)?;
+
+    // Tenant IDs may contain characters that need encoding in a path segment
+    let endpoint = |segments: &[&str]| -> anyhow::Result<reqwest::Url> {
+        let mut endpoint = base.clone();
+
+        endpoint
+            .path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("Invalid analyzer URL '{}'", url))?
+            .pop_if_empty()
+            .extend(segments);
+
+        Ok(endpoint)
+    };
+
+    let tenants = client
+        .get(endpoint(&["api", "v1", "tenants"])?)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Vec<Tenant>>()
+        .await?;
+
+    let mut rows = Vec::new();
+
+    for Tenant { cluster, tenant } in tenants {
+        let metrics = client
+            .get(endpoint(&["api", "v1", "tenants", &tenant, "metrics"])?)
+            .query(&[("cluster", cluster)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Vec<Row>>()
+            .await?;
+
+        rows.extend(metrics);
+    }
+
+    Ok(rows)
+}
+
+/// Render rows in the given format
+pub fn render(format: Format, rows: &[Row]) -> anyhow::Result<String> {
+    match format {
+        Format::Json => Ok(serde_json::to_string_pretty(rows)? + "\n"),
+        Format::Csv => Ok(render_csv(rows)),
+        Format::Table => Ok(render_table(rows)),
+    }
+}
+
+const HEADER: [&str; 7] = [
+    "cluster", "tenant", "metric", "status", "series", "used_via", "depth",
+];
+
+/// Get the fields of a row, in the order of the header
+fn fields(row: &Row) -> [String; 7] {
+    [
+        row.cluster.clone(),
+        row.tenant.clone(),
+        row.metric.clone(),
+        row.status.to_string(),
+        row.series_count.to_string(),
+        row.used_via.clone().unwrap_or_default(),
+        row.depth.map(|d| d.to_string()).unwrap_or_default(),
+    ]
+}
+
+fn render_csv(rows: &[Row]) -> String {
+    let mut output = HEADER.join(",") + "\n";
+
+    for row in rows {
+        let fields: Vec<String> = fields(row).iter().map(|f| escape_csv(f)).collect();
+        let _ = writeln!(output, "{}", fields.join(","));
+    }
+
+    output
+}
+
+fn render_table(rows: &[Row]) -> String {
+    let header = HEADER.map(|h| h.to_uppercase());
+    let rows: Vec<[String; 7]> = rows.iter().map(fields).collect();
+
+    let mut widths = header.clone().map(|h| h.len());
+
+    for row in &rows {
+        for (width, field) in widths.iter_mut().zip(row) {
+            *width = (*width).max(field.len());
+        }
+    }
+
+    let mut output = String::new();
+
+    for row in std::iter::once(&header).chain(&rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(field, width)| format!("{:<width$}", field, width = width))
+            .collect();
+
+        let _ = writeln!(output, "{}", line.join("  ").trim_end());
+    }
+
+    output
+}
+
+/// Quote a CSV field if it contains a separator, quote or newline
+fn escape_csv(field: &str) -> String {
+    match field.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
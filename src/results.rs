@@ -0,0 +1,526 @@
+use crate::{
+    aggregation::{LabelResult, Recommendation},
+    mimir::tenant::Tenant,
+    usage::{Source, Usage},
+};
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::RwLock,
+};
+
+/// Results of the latest analysis cycle, shared with the HTTP server
+pub static RESULTS: Lazy<ResultStore> = Lazy::new(ResultStore::default);
+
+/// Analysis result of a single metric
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricResult {
+    pub series_count: usize,
+    /// How the metric is used, or `None` if it is unused
+    pub usage: Option<Usage>,
+    /// Everything referencing the metric
+    pub sources: Vec<Source>,
+    /// The metric's labels, if they were analyzed
+    #[serde(default)]
+    pub labels: Vec<LabelResult>,
+    pub recommendation: Option<Recommendation>,
+    /// When the analyzer first saw the metric, if history is enabled
+    #[serde(default)]
+    pub first_seen: Option<DateTime<Utc>>,
+    /// When the metric became unused, if it is unused and history is enabled
+    #[serde(default)]
+    pub unused_since: Option<DateTime<Utc>>,
+    /// Whether the metric is unused but was first seen within the grace period
+    #[serde(default)]
+    pub in_grace_period: bool,
+    /// Whether the metric looks unused, but some usage sources failed to load
+    #[serde(default)]
+    pub usage_unknown: bool,
+    /// Growth of the series count, if history is enabled
+    #[serde(default)]
+    pub growth: SeriesGrowth,
+    /// Whether the series count grew past the exploding threshold
+    #[serde(default)]
+    pub exploding: bool,
+}
+
+/// Ratio of a metric's current series count to its series count in the past, such as `2.0` if it
+/// doubled. Unset if there is no cycle old enough to compare to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SeriesGrowth {
+    /// Compared to a day ago
+    #[serde(rename = "1d", skip_serializing_if = "Option::is_none")]
+    pub day: Option<f64>,
+    /// Compared to a week ago
+    #[serde(rename = "7d", skip_serializing_if = "Option::is_none")]
+    pub week: Option<f64>,
+}
+
+/// Whether a metric is used
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricStatus {
+    Used,
+    /// Unused, but too recently created to be flagged
+    New,
+    Unused,
+    /// Not referenced by the usage sources that loaded, while others failed to
+    Unknown,
+}
+
+/// Analysis results of a single tenant
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TenantResult {
+    pub analyzed_at: DateTime<Utc>,
+    pub metrics: BTreeMap<String, MetricResult>,
+    /// Usage sources that failed to load, leaving the analysis incomplete
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub incomplete_sources: Vec<String>,
+}
+
+/// Holds the analysis results of all tenants of every cluster.
+///
+/// Each cycle replaces the previous results of a cluster as a whole. Metrics and tenants that
+/// disappeared are kept for a retention period, after which they are dropped.
+#[derive(Debug, Default)]
+pub struct ResultStore {
+    tenants: RwLock<BTreeMap<Tenant, StoredTenant>>,
+}
+
+/// Results as saved to a file: tenant results per tenant, per cluster
+type SavedResults = BTreeMap<String, BTreeMap<String, TenantResult>>;
+
+#[derive(Debug, Clone)]
+struct StoredTenant {
+    result: TenantResult,
+    /// Metrics missing from the latest result, with the time they disappeared
+    disappeared_metrics: HashMap<String, (MetricResult, DateTime<Utc>)>,
+    /// Time the tenant itself disappeared
+    disappeared_at: Option<DateTime<Utc>>,
+}
+
+/// A view of a tenant's results, including recently disappeared metrics
+#[derive(Debug)]
+pub struct TenantView<'a> {
+    pub tenant: &'a Tenant,
+    pub result: &'a TenantResult,
+    pub disappeared_metrics: Vec<(&'a str, &'a MetricResult)>,
+}
+
+impl MetricResult {
+    pub fn status(&self) -> MetricStatus {
+        match (&self.usage, self.usage_unknown, self.in_grace_period) {
+            (Some(_), _, _) => MetricStatus::Used,
+            (None, true, _) => MetricStatus::Unknown,
+            (None, false, true) => MetricStatus::New,
+            (None, false, false) => MetricStatus::Unused,
+        }
+    }
+}
+
+impl SeriesGrowth {
+    /// The ratios with the window they cover, for the windows that have one
+    pub fn windows(&self) -> impl Iterator<Item = (&'static str, f64)> {
+        [("1d", self.day), ("7d", self.week)]
+            .into_iter()
+            .filter_map(|(window, ratio)| ratio.map(|ratio| (window, ratio)))
+    }
+}
+
+impl std::fmt::Display for MetricStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetricStatus::Used => write!(f, "used"),
+            MetricStatus::New => write!(f, "new"),
+            MetricStatus::Unused => write!(f, "unused"),
+            MetricStatus::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl TenantResult {
+    /// Create an empty result, analyzed now
+    pub fn new() -> Self {
+        Self {
+            analyzed_at: Utc::now(),
+            metrics: BTreeMap::new(),
+            incomplete_sources: Vec::new(),
+        }
+    }
+
+    /// Check whether every usage source loaded, so unused metrics are known to be unused
+    pub fn is_complete(&self) -> bool {
+        self.incomplete_sources.is_empty()
+    }
+
+    /// Number of series belonging to unused metrics
+    pub fn unused_series(&self) -> usize {
+        self.metrics
+            .values()
+            .filter(|m| m.status() == MetricStatus::Unused)
+            .map(|m| m.series_count)
+            .sum()
+    }
+}
+
+impl Default for TenantResult {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResultStore {
+    /// Replace the stored results of a cluster with those of a cycle.
+    ///
+    /// Tenants in `tenants` without a result in `results` failed to be analyzed, so their previous
+    /// results are kept as is. Stored tenants of the cluster not in `tenants` have disappeared.
+    pub fn replace(
+        &self,
+        cluster: &str,
+        tenants: &[Tenant],
+        mut results: HashMap<Tenant, TenantResult>,
+        retention: Duration,
+    ) {
+        let now = Utc::now();
+        let mut stored = self.tenants.write().unwrap();
+        let (previous, mut next): (BTreeMap<_, _>, BTreeMap<_, _>) = std::mem::take(&mut *stored)
+            .into_iter()
+            .partition(|(tenant, _)| tenant.cluster == cluster);
+
+        for (tenant, mut previous) in previous {
+            match results.remove(&tenant) {
+                Some(result) => {
+                    let disappeared = previous
+                        .result
+                        .metrics
+                        .into_iter()
+                        .filter(|(metric, _)| !result.metrics.contains_key(metric))
+                        .map(|(metric, value)| (metric, (value, now)))
+                        .chain(previous.disappeared_metrics)
+                        .filter(|(metric, (_, at))| {
+                            !result.metrics.contains_key(metric) && now - *at < retention
+                        })
+                        .collect();
+
+                    next.insert(
+                        tenant,
+                        StoredTenant {
+                            result,
+                            disappeared_metrics: disappeared,
+                            disappeared_at: None,
+                        },
+                    );
+                }
+                None if tenants.contains(&tenant) => {
+                    previous.disappeared_at = None;
+                    next.insert(tenant, previous);
+                }
+                None => {
+                    let disappeared_at = *previous.disappeared_at.get_or_insert(now);
+
+                    if now - disappeared_at < retention {
+                        next.insert(tenant, previous);
+                    }
+                }
+            }
+        }
+
+        // Tenants seen for the first time
+        for (tenant, result) in results {
+            next.insert(
+                tenant,
+                StoredTenant {
+                    result,
+                    disappeared_metrics: HashMap::new(),
+                    disappeared_at: None,
+                },
+            );
+        }
+
+        *stored = next;
+    }
+
+    /// Forget the results of clusters that are no longer configured
+    pub fn retain_clusters(&self, clusters: &[&str]) {
+        let mut stored = self.tenants.write().unwrap();
+
+        stored.retain(|tenant, _| clusters.contains(&tenant.cluster.as_str()));
+    }
+
+    /// Get the latest result of a tenant
+    pub fn get(&self, tenant: &Tenant) -> Option<TenantResult> {
+        let stored = self.tenants.read().unwrap();
+
+        stored.get(tenant).map(|stored| stored.result.clone())
+    }
+
+    /// Get the clusters a tenant ID has results in
+    pub fn clusters_of(&self, id: &str) -> Vec<String> {
+        let stored = self.tenants.read().unwrap();
+
+        stored
+            .keys()
+            .filter(|tenant| tenant.id == id)
+            .map(|tenant| tenant.cluster.clone())
+            .collect()
+    }
+
+    /// Save the latest result of every tenant to a file, so they survive restarts.
+    ///
+    /// The file is written next to its destination first and then renamed over it, so that a crash
+    /// while saving doesn't leave a truncated file behind.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let json = {
+            let stored = self.tenants.read().unwrap();
+            let mut results: BTreeMap<&str, BTreeMap<&str, &TenantResult>> = BTreeMap::new();
+
+            for (tenant, stored) in stored.iter() {
+                results
+                    .entry(&tenant.cluster)
+                    .or_default()
+                    .insert(&tenant.id, &stored.result);
+            }
+
+            serde_json::to_vec(&results)?
+        };
+
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, json)?;
+        std::fs::rename(&tmp, path)?;
+
+        Ok(())
+    }
+
+    /// Load the results saved by [`ResultStore::save`], replacing the stored results. Returns the
+    /// number of tenants loaded, or 0 if the file doesn't exist.
+    pub fn load(&self, path: &Path) -> anyhow::Result<usize> {
+        let json = match std::fs::read(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let results: SavedResults = serde_json::from_slice(&json)?;
+
+        let results: Vec<(Tenant, TenantResult)> = results
+            .into_iter()
+            .flat_map(|(cluster, tenants)| {
+                tenants
+                    .into_iter()
+                    .map(move |(id, result)| (Tenant::new(cluster.clone(), id), result))
+            })
+            .collect();
+        let count = results.len();
+
+        *self.tenants.write().unwrap() = results
+            .into_iter()
+            .map(|(tenant, result)| {
+                let stored = StoredTenant {
+                    result,
+                    disappeared_metrics: HashMap::new(),
+                    disappeared_at: None,
+                };
+
+                (tenant, stored)
+            })
+            .collect();
+
+        Ok(count)
+    }
+
+    /// Visit all stored tenants, including recently disappeared ones
+    pub fn visit(&self, mut f: impl FnMut(TenantView<'_>)) {
+        let stored = self.tenants.read().unwrap();
+
+        for (tenant, stored) in stored.iter() {
+            f(TenantView {
+                tenant,
+                result: &stored.result,
+                disappeared_metrics: stored
+                    .disappeared_metrics
+                    .iter()
+                    .map(|(metric, (value, _))| (metric.as_str(), value))
+                    .collect(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(series_count: usize) -> MetricResult {
+        MetricResult {
+            series_count,
+            usage: None,
+            sources: Vec::new(),
+            labels: Vec::new(),
+            recommendation: None,
+            first_seen: None,
+            unused_since: None,
+            in_grace_period: false,
+            usage_unknown: false,
+            growth: SeriesGrowth::default(),
+            exploding: false,
+        }
+    }
+
+    fn result(metrics: &[&str]) -> TenantResult {
+        TenantResult {
+            metrics: metrics
+                .iter()
+                .map(|m| (m.to_string(), metric(10)))
+                .collect(),
+            ..TenantResult::new()
+        }
+    }
+
+    /// The metrics of each stored tenant, with the metrics that disappeared from it
+    fn stored(store: &ResultStore) -> Vec<(Tenant, Vec<String>, Vec<String>)> {
+        let mut tenants = Vec::new();
+
+        store.visit(|view| {
+            let mut disappeared: Vec<String> = view
+                .disappeared_metrics
+                .iter()
+                .map(|(metric, _)| metric.to_string())
+                .collect();
+            disappeared.sort();
+
+            tenants.push((
+                view.tenant.clone(),
+                view.result.metrics.keys().cloned().collect(),
+                disappeared,
+            ));
+        });
+
+        tenants
+    }
+
+    #[test]
+    fn replace_keeps_disappeared_metrics_for_the_retention_period() {
+        let store = ResultStore::default();
+        let prod = Tenant::new("main", "prod");
+        let tenants = [prod.clone()];
+        let retention = Duration::hours(1);
+
+        store.replace(
+            "main",
+            &tenants,
+            HashMap::from([(prod.clone(), result(&["a", "b"]))]),
+            retention,
+        );
+        store.replace(
+            "main",
+            &tenants,
+            HashMap::from([(prod.clone(), result(&["a"]))]),
+            retention,
+        );
+
+        assert_eq!(
+            stored(&store),
+            [(prod.clone(), vec!["a".to_string()], vec!["b".to_string()])]
+        );
+
+        // A metric that comes back is no longer disappeared
+        store.replace(
+            "main",
+            &tenants,
+            HashMap::from([(prod.clone(), result(&["a", "b"]))]),
+            retention,
+        );
+
+        assert_eq!(
+            stored(&store),
+            [(prod.clone(), vec!["a".to_string(), "b".to_string()], vec![])]
+        );
+
+        // Without retention, disappeared metrics are dropped right away
+        store.replace(
+            "main",
+            &tenants,
+            HashMap::from([(prod.clone(), result(&["a"]))]),
+            Duration::zero(),
+        );
+
+        assert_eq!(stored(&store), [(prod, vec!["a".to_string()], vec![])]);
+    }
+
+    #[test]
+    fn replace_keeps_failed_and_disappeared_tenants() {
+        let store = ResultStore::default();
+        let (prod, dev) = (Tenant::new("main", "prod"), Tenant::new("main", "dev"));
+        let retention = Duration::hours(1);
+
+        store.replace(
+            "main",
+            &[prod.clone(), dev.clone()],
+            HashMap::from([
+                (prod.clone(), result(&["a"])),
+                (dev.clone(), result(&["b"])),
+            ]),
+            retention,
+        );
+
+        // A tenant that failed keeps its previous result
+        store.replace(
+            "main",
+            &[prod.clone(), dev.clone()],
+            HashMap::from([(prod.clone(), result(&["c"]))]),
+            retention,
+        );
+
+        assert_eq!(store.get(&dev).unwrap().metrics.len(), 1);
+        assert!(store.get(&prod).unwrap().metrics.contains_key("c"));
+
+        // A tenant that disappeared is kept for the retention period
+        store.replace(
+            "main",
+            std::slice::from_ref(&prod),
+            HashMap::from([(prod.clone(), result(&["c"]))]),
+            retention,
+        );
+        assert!(store.get(&dev).is_some());
+
+        store.replace(
+            "main",
+            std::slice::from_ref(&prod),
+            HashMap::from([(prod.clone(), result(&["c"]))]),
+            Duration::zero(),
+        );
+        assert!(store.get(&dev).is_none());
+    }
+
+    #[test]
+    fn replace_leaves_other_clusters_alone() {
+        let store = ResultStore::default();
+        let (us, eu) = (Tenant::new("us", "prod"), Tenant::new("eu", "prod"));
+
+        store.replace(
+            "us",
+            std::slice::from_ref(&us),
+            HashMap::from([(us.clone(), result(&["a"]))]),
+            Duration::zero(),
+        );
+        store.replace(
+            "eu",
+            std::slice::from_ref(&eu),
+            HashMap::from([(eu.clone(), result(&["b"]))]),
+            Duration::zero(),
+        );
+
+        assert!(store.get(&us).is_some());
+        assert_eq!(store.clusters_of("prod"), ["eu", "us"]);
+
+        store.retain_clusters(&["eu"]);
+
+        assert!(store.get(&us).is_none());
+        assert!(store.get(&eu).is_some());
+    }
+}
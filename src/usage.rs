@@ -0,0 +1,450 @@
+use crate::{
+    metrics::analysis::ExpressionSource,
+    mimir::tenant::Tenant,
+    promql::{MetricReferences, labels::LabelUsage},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+/// Where a set of metric references was found
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(tag = "source", rename_all = "camelCase")]
+pub enum Source {
+    /// A dashboard panel, or a template variable if `panel` is unset
+    Dashboard {
+        /// The Grafana instance and org the dashboard is in
+        #[serde(default)]
+        grafana: String,
+        #[serde(default)]
+        org: String,
+        uid: String,
+        title: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        panel: Option<String>,
+    },
+    /// A Grafana alert rule, with the folder and rule group it belongs to
+    Alert {
+        #[serde(default)]
+        grafana: String,
+        #[serde(default)]
+        org: String,
+        uid: String,
+        title: String,
+        #[serde(default)]
+        folder: String,
+        #[serde(default)]
+        group: String,
+    },
+    /// A ruler alerting or recording rule
+    Rule {
+        namespace: String,
+        group: String,
+        /// The alert name, or the recorded metric of a recording rule
+        rule: String,
+    },
+    /// Dashboards analyzed by mimirtool, which doesn't report which dashboard references a metric
+    Mimirtool,
+}
+
+/// Metric references attributed to the tenants they were found for
+#[derive(Debug, Clone, Default)]
+pub struct TenantUsage {
+    /// References that couldn't be attributed to specific tenants, credited to all of them
+    all: HashMap<Source, MetricReferences>,
+    tenants: HashMap<Tenant, HashMap<Source, MetricReferences>>,
+    /// Sources that failed to load, leaving the references of every tenant incomplete
+    failures: Vec<String>,
+    /// Expressions that couldn't be parsed, leaving the references of the tenants they would be
+    /// credited to incomplete, or of every tenant if unset
+    unparsed: Vec<(Option<Tenant>, String)>,
+}
+
+impl TenantUsage {
+    /// Credit references to every tenant
+    pub fn add_all(&mut self, source: Source, references: MetricReferences) {
+        self.all.entry(source).or_default().extend(references);
+    }
+
+    /// Credit references to a single tenant
+    pub fn add_tenant(&mut self, tenant: &Tenant, source: Source, references: MetricReferences) {
+        self.tenants
+            .entry(tenant.clone())
+            .or_default()
+            .entry(source)
+            .or_default()
+            .extend(references);
+    }
+
+    /// Merge the references of another usage into this one
+    pub fn extend(&mut self, other: TenantUsage) {
+        for (source, references) in other.all {
+            self.add_all(source, references);
+        }
+
+        for (tenant, sources) in other.tenants {
+            for (source, references) in sources {
+                self.add_tenant(&tenant, source, references);
+            }
+        }
+
+        self.failures.extend(other.failures);
+        self.unparsed.extend(other.unparsed);
+    }
+
+    /// Record a source that failed to load
+    pub fn fail(&mut self, failure: String) {
+        self.failures.push(failure);
+    }
+
+    /// The sources that failed to load, if any
+    pub fn failures(&self) -> &[String] {
+        &self.failures
+    }
+
+    /// Record an expression that couldn't be parsed, for the tenants it would be credited to or
+    /// every tenant if unset
+    pub fn unparsed(&mut self, tenants: Option<&[Tenant]>, failure: String) {
+        match tenants {
+            Some(tenants) => {
+                let failures = tenants.iter().map(|t| (Some(t.clone()), failure.clone()));
+                self.unparsed.extend(failures);
+            }
+            None => self.unparsed.push((None, failure)),
+        }
+    }
+
+    /// Get everything leaving the references of a tenant incomplete: sources that failed to load
+    /// and expressions that couldn't be parsed
+    pub fn failures_for<'a>(&'a self, tenant: &'a Tenant) -> impl Iterator<Item = &'a String> {
+        let unparsed = self
+            .unparsed
+            .iter()
+            .filter(move |(t, _)| t.as_ref().is_none_or(|t| t == tenant))
+            .map(|(_, failure)| failure);
+
+        self.failures.iter().chain(unparsed)
+    }
+
+    /// Get the references credited to a tenant, by source
+    pub fn for_tenant(
+        &self,
+        tenant: &Tenant,
+    ) -> impl Iterator<Item = (&Source, &MetricReferences)> {
+        self.all
+            .iter()
+            .chain(self.tenants.get(tenant).into_iter().flatten())
+    }
+}
+
+/// Metrics referenced by a set of ruler rules
+#[derive(Debug, Clone, Default)]
+pub struct RuleUsage {
+    /// Metrics referenced by each alerting rule
+    pub alerting: Vec<(Source, MetricReferences)>,
+    pub recording: Vec<RecordingRule>,
+    /// Rules whose expression couldn't be parsed
+    pub unparsed: Vec<String>,
+}
+
+/// A recording rule, writing the result of an expression to a new metric
+#[derive(Debug, Clone)]
+pub struct RecordingRule {
+    /// Name of the recorded metric
+    pub record: String,
+    pub source: Source,
+    /// Metrics the rule's expression references
+    pub references: MetricReferences,
+}
+
+/// How a metric is used
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Usage {
+    /// Referenced directly by a dashboard, alert or alerting rule
+    Direct,
+    /// Referenced by a recording rule whose output is used, `depth` recording rules away from a
+    /// direct consumer
+    Via { record: String, depth: usize },
+}
+
+/// Dependency graph of metrics through recording rules.
+///
+/// A metric counts as used if it is referenced directly by a consumer, or by a recording rule
+/// whose output is itself used.
+pub struct UsageGraph<'a> {
+    consumers: ConsumerIndex<'a>,
+    /// Recording rules leading to a consumer, with their distance from it, in order of distance
+    live_rules: Vec<(&'a RecordingRule, usize)>,
+}
+
+/// The references of direct consumers, indexed by metric name
+struct ConsumerIndex<'a> {
+    names: HashMap<&'a str, Vec<(&'a Source, &'a MetricReferences)>>,
+    /// Consumers referencing metrics through patterns, which can't be indexed
+    patterns: Vec<(&'a Source, &'a MetricReferences)>,
+}
+
+impl Source {
+    /// The kind of expression the references were found in
+    pub fn kind(&self) -> ExpressionSource {
+        match self {
+            Source::Dashboard { .. } | Source::Mimirtool => ExpressionSource::Dashboard,
+            Source::Alert { .. } => ExpressionSource::Alert,
+            Source::Rule { .. } => ExpressionSource::Rule,
+        }
+    }
+
+    /// The Grafana org the references were found in, if found in Grafana
+    pub fn org(&self) -> Option<&str> {
+        match self {
+            Source::Dashboard { org, .. } | Source::Alert { org, .. } => Some(org),
+            Source::Rule { .. } | Source::Mimirtool => None,
+        }
+    }
+}
+
+impl<'a> ConsumerIndex<'a> {
+    fn new(consumers: Vec<(&'a Source, &'a MetricReferences)>) -> Self {
+        let mut names: HashMap<&str, Vec<(&Source, &MetricReferences)>> = HashMap::new();
+
+        for &(source, references) in &consumers {
+            for name in references.names() {
+                names.entry(name).or_default().push((source, references));
+            }
+        }
+
+        let patterns = consumers
+            .into_iter()
+            .filter(|(_, references)| references.has_patterns())
+            .collect();
+
+        Self { names, patterns }
+    }
+
+    /// Get the consumers referencing a metric
+    fn consumers(&self, metric: &str) -> impl Iterator<Item = (&'a Source, &'a MetricReferences)> {
+        let named = self.names.get(metric).into_iter().flatten().copied();
+        let patterned = self
+            .patterns
+            .iter()
+            .filter(move |(_, references)| references.matches_pattern(metric))
+            .copied();
+
+        named.chain(patterned)
+    }
+
+    fn contains(&self, metric: &str) -> bool {
+        self.consumers(metric).next().is_some()
+    }
+}
+
+impl<'a> UsageGraph<'a> {
+    /// Build the graph from the references of direct consumers and all recording rules
+    pub fn new(
+        consumers: Vec<(&'a Source, &'a MetricReferences)>,
+        rules: &'a [RecordingRule],
+    ) -> Self {
+        let consumers = ConsumerIndex::new(consumers);
+        let mut live_rules: Vec<(&RecordingRule, usize)> = Vec::new();
+        let mut pending: Vec<&RecordingRule> = rules.iter().collect();
+
+        // Rules whose output is consumed directly
+        pending.retain(|rule| {
+            let consumed = consumers.contains(&rule.record);
+
+            if consumed {
+                live_rules.push((rule, 1));
+            }
+
+            !consumed
+        });
+
+        // Walk breadth-first towards the sources, one recording rule at a time
+        let mut next = 0;
+
+        while next < live_rules.len() {
+            let (live, depth) = live_rules[next];
+            next += 1;
+
+            pending.retain(|rule| {
+                let consumed = live.references.contains(&rule.record);
+
+                if consumed {
+                    live_rules.push((rule, depth + 1));
+                }
+
+                !consumed
+            });
+        }
+
+        Self {
+            consumers,
+            live_rules,
+        }
+    }
+
+    /// Get how a metric is used, if at all
+    pub fn usage(&self, metric: &str) -> Option<Usage> {
+        if self.consumers.contains(metric) {
+            return Some(Usage::Direct);
+        }
+
+        // Live rules are ordered by depth, so the first match is the shortest chain
+        self.live_rules
+            .iter()
+            .find(|(rule, _)| rule.references.contains(metric))
+            .map(|(rule, depth)| Usage::Via {
+                record: rule.record.clone(),
+                depth: *depth,
+            })
+    }
+
+    /// Get everything referencing a metric: direct consumers, and recording rules whose output is used
+    pub fn sources(&self, metric: &str) -> Vec<Source> {
+        let rules = self
+            .live_rules
+            .iter()
+            .filter(|(rule, _)| rule.references.contains(metric))
+            .map(|(rule, _)| &rule.source);
+
+        self.consumers
+            .consumers(metric)
+            .map(|(source, _)| source)
+            .chain(rules)
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// Get the labels of a metric used by its direct consumers and the recording rules it is used
+    /// through, if it is used at all
+    pub fn labels(&self, metric: &str) -> Option<LabelUsage> {
+        let rules = self.live_rules.iter().map(|(rule, _)| &rule.references);
+
+        self.consumers
+            .consumers(metric)
+            .map(|(_, references)| references)
+            .chain(rules)
+            .filter_map(|references| references.labels(metric))
+            .reduce(|acc, labels| acc.union(&labels))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dashboard(title: &str) -> Source {
+        Source::Dashboard {
+            grafana: "grafana".to_string(),
+            org: "Main Org.".to_string(),
+            uid: title.to_lowercase(),
+            title: title.to_string(),
+            panel: None,
+        }
+    }
+
+    fn rule(record: &str, expr: &str) -> RecordingRule {
+        RecordingRule {
+            record: record.to_string(),
+            source: Source::Rule {
+                namespace: "rules".to_string(),
+                group: "group".to_string(),
+                rule: record.to_string(),
+            },
+            references: MetricReferences::from_expr(expr).unwrap(),
+        }
+    }
+
+    #[test]
+    fn usage_is_resolved_through_recording_rules() {
+        let source = dashboard("Overview");
+        let references = MetricReferences::from_expr("job:requests:rate5m").unwrap();
+        let rules = [
+            rule(
+                "job:requests:rate5m",
+                "sum by (job) (instance:requests:rate5m)",
+            ),
+            rule("instance:requests:rate5m", "rate(requests_total[5m])"),
+            rule("job:errors:rate5m", "sum by (job) (rate(errors_total[5m]))"),
+        ];
+        let graph = UsageGraph::new(vec![(&source, &references)], &rules);
+
+        assert_eq!(graph.usage("job:requests:rate5m"), Some(Usage::Direct));
+        assert_eq!(
+            graph.usage("instance:requests:rate5m"),
+            Some(Usage::Via {
+                record: "job:requests:rate5m".to_string(),
+                depth: 1
+            })
+        );
+        assert_eq!(
+            graph.usage("requests_total"),
+            Some(Usage::Via {
+                record: "instance:requests:rate5m".to_string(),
+                depth: 2
+            })
+        );
+
+        // Rules whose output nothing uses don't make their inputs used
+        assert_eq!(graph.usage("job:errors:rate5m"), None);
+        assert_eq!(graph.usage("errors_total"), None);
+
+        assert_eq!(graph.sources("requests_total"), [rules[1].source.clone()]);
+        assert_eq!(graph.sources("job:requests:rate5m"), vec![source.clone()]);
+    }
+
+    #[test]
+    fn shortest_chain_is_reported() {
+        let source = dashboard("Overview");
+        let references = MetricReferences::from_expr("a:sum + b:sum").unwrap();
+        let rules = [
+            rule("a:sum", "sum(c:sum)"),
+            rule("c:sum", "sum(requests_total)"),
+            rule("b:sum", "sum(requests_total)"),
+        ];
+        let graph = UsageGraph::new(vec![(&source, &references)], &rules);
+
+        assert_eq!(
+            graph.usage("requests_total"),
+            Some(Usage::Via {
+                record: "b:sum".to_string(),
+                depth: 1
+            })
+        );
+    }
+
+    #[test]
+    fn cyclic_rules_terminate() {
+        let source = dashboard("Overview");
+        let references = MetricReferences::from_expr("a").unwrap();
+        let rules = [rule("a", "b"), rule("b", "a + c")];
+        let graph = UsageGraph::new(vec![(&source, &references)], &rules);
+
+        assert_eq!(graph.usage("a"), Some(Usage::Direct));
+        assert_eq!(
+            graph.usage("c"),
+            Some(Usage::Via {
+                record: "b".to_string(),
+                depth: 2
+            })
+        );
+    }
+
+    #[test]
+    fn patterns_and_labels_count_through_rules() {
+        let source = dashboard("Overview");
+        let references = MetricReferences::from_expr(r#"{__name__=~"job:.*"}"#).unwrap();
+        let rules = [rule("job:requests:sum", "sum by (job) (requests_total)")];
+        let graph = UsageGraph::new(vec![(&source, &references)], &rules);
+
+        assert_eq!(graph.usage("job:requests:sum"), Some(Usage::Direct));
+        assert!(graph.usage("requests_total").is_some());
+        assert_eq!(
+            graph.labels("requests_total"),
+            Some(LabelUsage::Only(BTreeSet::from(["job".to_string()])))
+        );
+        assert_eq!(graph.labels("unused_total"), None);
+    }
+}